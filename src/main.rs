@@ -1,24 +1,46 @@
 // ════════════════════════════════════════════════════════════
 // port-sentinel-rs v2.2.6 - Port Sentinel Monitoring System
 // Features: Async Concurrent Detection | WeCom Alert | Graceful Shutdown
-//           Auto Config Initialization | Permission Protection | Env Var Injection
+//           CLI (run/check/validate/init) | Permission Protection | Env Var Injection
 // ════════════════════════════════════════════════════════════
 
 #![warn(rust_2018_idioms)]
 #![allow(dead_code)]
 
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use chrono::Local;
-use serde::Deserialize;
-use std::collections::HashMap;
+use clap::{Parser, Subcommand};
+use hmac::{Hmac, Mac};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use socket2::{Domain, Socket, Type};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::path::Path;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
-use tokio::net::TcpStream;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{Mutex, Semaphore};
 use tokio::time::{sleep, timeout};
-use tracing::{error, info, warn, Level};
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
 // ────────────────────────────────────────────────────────────
@@ -28,8 +50,65 @@ use tracing_subscriber::FmtSubscriber;
 #[derive(Debug, Deserialize, Clone)]
 struct Config {
     settings: Settings,
-    #[serde(rename = "device")]
+    #[serde(default, rename = "device")]
     devices: Vec<Device>,
+    #[serde(default, rename = "group")]
+    groups: HashMap<String, GroupSettings>,
+    #[serde(default, rename = "notifier")]
+    notifiers: Vec<NotifierConfig>,
+    /// Main config file, included files and the directories they were globbed from
+    #[serde(skip)]
+    sources: Vec<PathBuf>,
+}
+
+/// A file pulled in by `settings.include`: only `[[device]]` tables are allowed
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludeFile {
+    #[serde(default, rename = "device")]
+    devices: Vec<Device>,
+}
+
+/// Just the `[[device]]` ids with their position, for duplicate reports
+#[derive(Debug, Deserialize)]
+struct DeviceIdSpans {
+    #[serde(default)]
+    device: Vec<DeviceIdSpan>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceIdSpan {
+    id: toml::Spanned<String>,
+}
+
+impl Config {
+    /// Whether alerts of this group are merged into one digest message per round
+    fn digest_enabled(&self, group: &str) -> bool {
+        self.groups.get(group).map(|g| g.digest).unwrap_or(false)
+    }
+
+    /// All alert destinations: the legacy `settings.webhook` (as WeCom) plus `[[notifier]]` entries
+    fn notifier_configs(&self) -> Vec<NotifierConfig> {
+        let mut configs = Vec::new();
+        if !self.settings.webhook.is_empty() {
+            configs.push(NotifierConfig {
+                name: "wecom".to_string(),
+                retry: RetryPolicy::default(),
+                kind: NotifierKind::Wecom {
+                    url: self.settings.webhook.clone(),
+                },
+            });
+        }
+
+        for (idx, cfg) in self.notifiers.iter().enumerate() {
+            let mut cfg = cfg.clone();
+            if cfg.name.is_empty() {
+                cfg.name = format!("{}#{}", cfg.kind.type_name(), idx + 1);
+            }
+            configs.push(cfg);
+        }
+        configs
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -37,11 +116,24 @@ struct Settings {
     interval: u64,
     timeout: u64,
     alert_cooldown: u64,
+    #[serde(default)]
     webhook: String,
     #[serde(default = "default_log_level")]
     log_level: String,
     #[serde(default = "default_max_concurrent")]
     max_concurrent_connections: usize,
+    #[serde(default = "default_threshold")]
+    failure_threshold: u32,
+    #[serde(default = "default_threshold")]
+    recovery_threshold: u32,
+    #[serde(default = "default_state_file")]
+    state_file: String,
+    /// Prometheus listen address (e.g. "0.0.0.0:9898"), empty disables /metrics
+    #[serde(default)]
+    metrics_listen: String,
+    /// Extra `[[device]]` files (glob patterns, relative to the main config's directory)
+    #[serde(default)]
+    include: Vec<String>,
 }
 
 fn default_log_level() -> String {
@@ -52,287 +144,3834 @@ fn default_max_concurrent() -> usize {
     100
 }
 
+fn default_threshold() -> u32 {
+    1
+}
+
+fn default_state_file() -> String {
+    "data/state.json".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct NotifierConfig {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    retry: RetryPolicy,
+    #[serde(flatten)]
+    kind: NotifierKind,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum NotifierKind {
+    /// WeCom group robot (markdown message)
+    Wecom { url: String },
+    /// Generic HTTP endpoint: the alert event as JSON, or a user-defined template
+    Webhook {
+        url: String,
+        #[serde(default = "default_webhook_method")]
+        method: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        /// Body template with `{{ var }}` / `{{ var | json }}` placeholders
+        #[serde(default)]
+        body: Option<String>,
+    },
+    /// DingTalk custom robot (markdown message, optional HMAC-SHA256 signing)
+    Dingtalk {
+        url: String,
+        /// Robot "sign" secret (SEC...), enables `timestamp` + `sign` query parameters
+        #[serde(default)]
+        secret: String,
+        /// Robot "custom keyword", guaranteed to appear in title and text
+        #[serde(default)]
+        keyword: String,
+        #[serde(default)]
+        at_mobiles: Vec<String>,
+        #[serde(default)]
+        at_all: bool,
+    },
+    /// Feishu/Lark custom bot (interactive card, optional signature verification)
+    Feishu {
+        url: String,
+        /// Bot "signature verification" secret, adds `timestamp` + `sign` to the body
+        #[serde(default)]
+        secret: String,
+    },
+    /// SMTP mail (HTML + plain text alternative)
+    Email {
+        host: String,
+        /// Defaults to 25 / 587 / 465 for none / starttls / tls
+        #[serde(default)]
+        port: Option<u16>,
+        #[serde(default)]
+        tls: SmtpTls,
+        #[serde(default)]
+        username: String,
+        #[serde(default)]
+        password: String,
+        from: String,
+        /// Recipients of every alert
+        #[serde(default)]
+        to: Vec<String>,
+        /// Extra recipients by device group
+        #[serde(default)]
+        group_to: HashMap<String, Vec<String>>,
+        /// Extra recipients by device priority
+        #[serde(default)]
+        priority_to: HashMap<String, Vec<String>>,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SmtpTls {
+    /// Plain SMTP, e.g. a local relay or test sink
+    None,
+    #[default]
+    Starttls,
+    /// Implicit TLS (SMTPS)
+    Tls,
+}
+
+impl NotifierKind {
+    fn type_name(&self) -> &'static str {
+        match self {
+            NotifierKind::Wecom { .. } => "wecom",
+            NotifierKind::Webhook { .. } => "webhook",
+            NotifierKind::Dingtalk { .. } => "dingtalk",
+            NotifierKind::Feishu { .. } => "feishu",
+            NotifierKind::Email { .. } => "email",
+        }
+    }
+
+    /// Target URL of HTTP based notifiers
+    fn url(&self) -> Option<&str> {
+        match self {
+            NotifierKind::Wecom { url }
+            | NotifierKind::Webhook { url, .. }
+            | NotifierKind::Dingtalk { url, .. }
+            | NotifierKind::Feishu { url, .. } => Some(url),
+            NotifierKind::Email { .. } => None,
+        }
+    }
+
+    fn url_mut(&mut self) -> Option<&mut String> {
+        match self {
+            NotifierKind::Wecom { url }
+            | NotifierKind::Webhook { url, .. }
+            | NotifierKind::Dingtalk { url, .. }
+            | NotifierKind::Feishu { url, .. } => Some(url),
+            NotifierKind::Email { .. } => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
+struct RetryPolicy {
+    #[serde(default = "default_retry_attempts")]
+    attempts: u32,
+    /// Linear backoff: wait `backoff_ms * attempt` between attempts
+    #[serde(default = "default_retry_backoff_ms")]
+    backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: default_retry_attempts(),
+            backoff_ms: default_retry_backoff_ms(),
+        }
+    }
+}
+
+fn default_webhook_method() -> String {
+    "POST".to_string()
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct GroupSettings {
+    #[serde(default)]
+    digest: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct Device {
     id: String,
     name: String,
     group: String,
     priority: String,
+    /// IP addresses or hostnames; hostnames are resolved every round, see `resolve_targets`
     ips: Vec<String>,
     os: String,
     location: String,
     checks: Vec<CheckItem>,
+    #[serde(default)]
+    failure_threshold: Option<u32>,
+    #[serde(default)]
+    recovery_threshold: Option<u32>,
+    /// Default `ip_policy` of the device's checks
+    #[serde(default)]
+    ip_policy: Option<IpPolicy>,
+    /// Override `settings.interval` / `timeout` / `alert_cooldown` (seconds) for this device
+    #[serde(default)]
+    interval: Option<u64>,
+    #[serde(default)]
+    timeout: Option<u64>,
+    #[serde(default)]
+    alert_cooldown: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Device {
+    /// Consecutive failed rounds required before the device counts as failed
+    fn failure_threshold(&self, settings: &Settings) -> u32 {
+        self.failure_threshold.unwrap_or(settings.failure_threshold)
+    }
+
+    /// Consecutive successful rounds required before a failed device counts as recovered
+    fn recovery_threshold(&self, settings: &Settings) -> u32 {
+        self.recovery_threshold
+            .unwrap_or(settings.recovery_threshold)
+    }
+
+    /// Seconds between runs of a check: `check.interval`, then `device.interval`, then `default`
+    fn check_interval(&self, check: &CheckItem, default: u64) -> u64 {
+        check.interval.or(self.interval).unwrap_or(default)
+    }
+
+    /// Probe timeout of a check: `check.timeout`, then `device.timeout`, then `default`
+    fn check_timeout(&self, check: &CheckItem, default: u64) -> u64 {
+        check.timeout.or(self.timeout).unwrap_or(default)
+    }
+
+    /// Alert cooldown of a failing check: `check.alert_cooldown`, then the device's, then `default`
+    fn check_cooldown(&self, check: &CheckItem, default: u64) -> u64 {
+        check
+            .alert_cooldown
+            .or(self.alert_cooldown)
+            .unwrap_or(default)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct CheckItem {
+    /// Required for every type except icmp
+    #[serde(default)]
     port: u16,
     #[serde(default)]
     name: String,
+    #[serde(rename = "type", default)]
+    kind: CheckKind,
+    #[serde(default)]
+    protocol: Protocol,
+    /// Overrides `Device.ip_policy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip_policy: Option<IpPolicy>,
+    /// http / tls: skip certificate verification (self-signed certs, or https to a bare IP);
+    /// tls checks still report the leaf's expiry
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    insecure: bool,
+    /// Override the device's interval / timeout / alert_cooldown for this check (seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alert_cooldown: Option<u64>,
+    #[serde(flatten)]
+    udp: UdpCheck,
+    #[serde(flatten)]
+    http: HttpCheck,
+    #[serde(flatten)]
+    tls: TlsCheck,
+    #[serde(flatten)]
+    banner: BannerCheck,
+    #[serde(flatten)]
+    redis: RedisCheck,
+    #[serde(flatten)]
+    postgres: PostgresCheck,
+    #[serde(flatten)]
+    icmp: IcmpCheck,
+    #[serde(flatten)]
+    auth: CheckAuth,
+}
+
+impl CheckItem {
+    fn display_name(&self) -> String {
+        if !self.name.is_empty() {
+            return self.name.clone();
+        }
+        match (self.protocol, self.kind) {
+            (Protocol::Udp, _) => format!("{}:{}", self.udp.mode().as_str(), self.port),
+            (_, CheckKind::Tcp) => format!("port:{}", self.port),
+            (_, CheckKind::Icmp) => "icmp".to_string(),
+            (_, kind) => format!("{}:{}", kind.as_str(), self.port),
+        }
+    }
+}
+
+/// How many of a device's targets must pass: `"any"`, `"all"` or `{ quorum = N }`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum IpPolicy {
+    #[default]
+    Any,
+    All,
+    Quorum(usize),
+}
+
+impl IpPolicy {
+    /// Passing targets required out of `total`
+    fn required(&self, total: usize) -> usize {
+        match self {
+            IpPolicy::Any => 1,
+            IpPolicy::All => total,
+            IpPolicy::Quorum(n) => *n,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    #[default]
+    Tcp,
+    /// Datagram probe, see `UdpCheck`; only valid with the default `type`
+    Udp,
+}
+
+/// Options of `protocol = "udp"` checks (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct UdpCheck {
+    /// dns | ntp | raw (`send` payload, optional `expect` regex); defaults to raw
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<UdpMode>,
+    /// Name looked up in dns mode, defaults to the root zone "."
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+}
+
+impl UdpCheck {
+    fn mode(&self) -> UdpMode {
+        self.mode.unwrap_or(UdpMode::Raw)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum UdpMode {
+    Dns,
+    Ntp,
+    Raw,
+}
+
+impl UdpMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            UdpMode::Dns => "dns",
+            UdpMode::Ntp => "ntp",
+            UdpMode::Raw => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum CheckKind {
+    /// TCP handshake only
+    #[default]
+    Tcp,
+    /// HTTP(S) request with status / body assertions
+    Http,
+    /// TLS handshake with chain validation and certificate expiry warning
+    Tls,
+    /// Send optional bytes, expect a regex in what the server answers
+    Banner,
+    /// Redis PING, optional AUTH and replication role assertion
+    Redis,
+    /// MySQL/MariaDB initial handshake (server version), optional login without password
+    Mysql,
+    /// PostgreSQL SSLRequest + StartupMessage round trip
+    Postgres,
+    /// ICMP echo (ping) with packet loss and RTT, no port
+    Icmp,
+}
+
+impl CheckKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CheckKind::Tcp => "tcp",
+            CheckKind::Http => "http",
+            CheckKind::Tls => "tls",
+            CheckKind::Banner => "banner",
+            CheckKind::Redis => "redis",
+            CheckKind::Mysql => "mysql",
+            CheckKind::Postgres => "postgres",
+            CheckKind::Icmp => "icmp",
+        }
+    }
+}
+
+/// Options of `type = "http"` checks (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct HttpCheck {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    /// Accepted status codes, empty means any 2xx/3xx
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    expect_status: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_contains: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<String, String>,
+    /// Virtual host for the Host header and SNI; the request still goes to each device IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    https: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    follow_redirects: bool,
+}
+
+impl HttpCheck {
+    fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/")
+    }
+
+    fn method(&self) -> String {
+        self.method.as_deref().unwrap_or("GET").to_uppercase()
+    }
+
+    fn status_ok(&self, status: u16) -> bool {
+        if self.expect_status.is_empty() {
+            (200..400).contains(&status)
+        } else {
+            self.expect_status.contains(&status)
+        }
+    }
+}
+
+/// Options of `type = "tls"` checks (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct TlsCheck {
+    /// Server name for SNI and certificate verification, defaults to the probed IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sni: Option<String>,
+    /// Warn when the certificate expires within this many days (default 14)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expiry_warn_days: Option<u32>,
+    /// PEM bundle of extra trust anchors (internal CA), trusted besides the Mozilla roots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_file: Option<String>,
+}
+
+impl TlsCheck {
+    fn expiry_warn_days(&self) -> u32 {
+        self.expiry_warn_days.unwrap_or(14)
+    }
+}
+
+/// Options of `type = "banner"` checks (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct BannerCheck {
+    /// Bytes written right after connecting (TOML escapes like "\r\n" work)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    send: Option<String>,
+    /// Regex the received bytes must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expect: Option<String>,
+    /// How long to wait for the match, defaults to the check's effective timeout
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expect_within_ms: Option<u64>,
+}
+
+/// Options of `type = "redis"` checks (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct RedisCheck {
+    /// Expected `INFO replication` role: master | replica
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+}
+
+/// Options of `type = "postgres"` checks (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct PostgresCheck {
+    /// Database named in the StartupMessage, defaults to the user name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database: Option<String>,
+}
+
+/// Options of `type = "icmp"` checks (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct IcmpCheck {
+    /// Echo requests sent per IP and round (default 3)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    count: Option<u32>,
+    /// Warn when packet loss exceeds this percentage; 100% loss is always a failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_loss: Option<u32>,
+    /// Warn when the average RTT exceeds this many milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_rtt_ms: Option<u64>,
+}
+
+impl IcmpCheck {
+    fn count(&self) -> u32 {
+        self.count.unwrap_or(3)
+    }
+}
+
+/// Credentials of protocol checks that log in (flattened into the check table)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct CheckAuth {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
 }
 
 // ────────────────────────────────────────────────────────────
 // Alert State Management (Thread-safe + Cooldown Control + State Recovery)
 // ────────────────────────────────────────────────────────────
 
-struct AlertState {
-    last_alert: HashMap<String, i64>,
-    is_failed: HashMap<String, bool>,
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct AlertState {
+    last_alert: HashMap<String, i64>,
+    is_failed: HashMap<String, bool>,
+    first_failure: HashMap<String, i64>,
+    last_failures: HashMap<String, Vec<CheckFailure>>,
+    fail_streak: HashMap<String, u32>,
+    ok_streak: HashMap<String, u32>,
+    alerted_severity: HashMap<String, Severity>,
+    /// Check indices that failed during each device's current failure streak (not persisted:
+    /// indices are only meaningful for the config in memory)
+    #[serde(skip)]
+    outage_checks: HashMap<String, BTreeSet<usize>>,
+}
+
+/// Outage summary produced when an alerted device comes back
+struct Recovery {
+    first_failure_ts: i64,
+    recovered_ts: i64,
+    failures: Vec<CheckFailure>,
+}
+
+impl Recovery {
+    fn duration_secs(&self) -> u64 {
+        (self.recovered_ts - self.first_failure_ts).max(0) as u64
+    }
+}
+
+impl AlertState {
+    fn new() -> Self {
+        Self {
+            last_alert: HashMap::new(),
+            is_failed: HashMap::new(),
+            first_failure: HashMap::new(),
+            last_failures: HashMap::new(),
+            fail_streak: HashMap::new(),
+            ok_streak: HashMap::new(),
+            alerted_severity: HashMap::new(),
+            outage_checks: HashMap::new(),
+        }
+    }
+
+    /// A failed evaluation built from the latest result of every check: `failing` are the
+    /// failing checks, `fresh` those that actually ran this round. Only a fresh failing check
+    /// advances the streak; on cached failures alone, returns whether the device is already
+    /// confirmed failed (so repeat alerts keep going).
+    fn record_failed_round(
+        &mut self,
+        device_id: &str,
+        failures: &[CheckFailure],
+        failing: &BTreeSet<usize>,
+        fresh: &HashSet<usize>,
+        now_ts: i64,
+        threshold: u32,
+    ) -> bool {
+        self.outage_checks
+            .entry(device_id.to_string())
+            .or_default()
+            .extend(failing);
+        if failing.iter().any(|i| fresh.contains(i)) {
+            self.record_failure(device_id, failures, now_ts, threshold)
+        } else {
+            self.fail_streak
+                .get(device_id)
+                .is_some_and(|streak| *streak >= threshold)
+        }
+    }
+
+    /// A passing evaluation: it counts toward recovery only when a check that failed during
+    /// the outage ran again this round
+    fn record_ok_round(
+        &mut self,
+        device_id: &str,
+        fresh: &HashSet<usize>,
+        now_ts: i64,
+        threshold: u32,
+    ) -> Option<Recovery> {
+        let counts = self
+            .outage_checks
+            .get(device_id)
+            .is_none_or(|outage| outage.iter().any(|i| fresh.contains(i)));
+        let recovery = if counts {
+            self.record_success(device_id, now_ts, threshold)
+        } else {
+            None
+        };
+        if !self.is_failed.contains_key(device_id) {
+            self.outage_checks.remove(device_id);
+        }
+        recovery
+    }
+
+    /// Record a failed round, return whether the consecutive failure threshold is reached
+    fn record_failure(
+        &mut self,
+        device_id: &str,
+        failures: &[CheckFailure],
+        now_ts: i64,
+        threshold: u32,
+    ) -> bool {
+        self.ok_streak.remove(device_id);
+        let streak = self.fail_streak.entry(device_id.to_string()).or_insert(0);
+        *streak += 1;
+        let confirmed = *streak >= threshold;
+
+        // First failure is the start of the streak, not the moment the threshold is crossed
+        self.first_failure
+            .entry(device_id.to_string())
+            .or_insert(now_ts);
+        self.last_failures
+            .insert(device_id.to_string(), failures.to_vec());
+        confirmed
+    }
+
+    /// Record a successful round, return the outage summary once the recovery threshold is reached
+    fn record_success(&mut self, device_id: &str, now_ts: i64, threshold: u32) -> Option<Recovery> {
+        self.fail_streak.remove(device_id);
+
+        // Never alerted: a pending failure streak is simply discarded (flap suppression)
+        if !self.is_failed.contains_key(device_id) {
+            self.ok_streak.remove(device_id);
+            self.first_failure.remove(device_id);
+            self.last_failures.remove(device_id);
+            return None;
+        }
+
+        let streak = self.ok_streak.entry(device_id.to_string()).or_insert(0);
+        *streak += 1;
+        if *streak < threshold {
+            return None;
+        }
+
+        self.ok_streak.remove(device_id);
+        self.mark_recovered(device_id, now_ts)
+    }
+
+    /// Restore state saved by a previous run
+    fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Save state atomically: write a temp file next to the target, then rename over it
+    fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let target = Path::new(path);
+        if let Some(dir) = target.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let tmp = target.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, target)?;
+        Ok(())
+    }
+
+    /// Drop entries of devices no longer in config, return the number of devices pruned
+    fn retain_devices(&mut self, device_ids: &HashSet<&str>) -> usize {
+        let stale: HashSet<String> = self
+            .last_alert
+            .keys()
+            .chain(self.is_failed.keys())
+            .chain(self.first_failure.keys())
+            .chain(self.last_failures.keys())
+            .chain(self.fail_streak.keys())
+            .chain(self.ok_streak.keys())
+            .chain(self.alerted_severity.keys())
+            .filter(|id| !device_ids.contains(id.as_str()))
+            .cloned()
+            .collect();
+
+        for id in &stale {
+            self.last_alert.remove(id);
+            self.is_failed.remove(id);
+            self.first_failure.remove(id);
+            self.last_failures.remove(id);
+            self.fail_streak.remove(id);
+            self.ok_streak.remove(id);
+            self.alerted_severity.remove(id);
+            self.outage_checks.remove(id);
+        }
+        stale.len()
+    }
+
+    /// Determine if alert should be sent (supports failure recovery detection + cooldown control).
+    /// Escalating from warning to error bypasses the cooldown.
+    fn should_alert(
+        &mut self,
+        device_id: &str,
+        currently_failed: bool,
+        severity: Severity,
+        now_ts: i64,
+        cooldown: u64,
+    ) -> bool {
+        let prev_failed = self.is_failed.get(device_id).copied().unwrap_or(false);
+
+        // If previously failed but now recovered, clear state without alert
+        if prev_failed && !currently_failed {
+            self.is_failed.remove(device_id);
+            return false;
+        }
+
+        // Only alert if failed and cooldown period has passed
+        if !currently_failed {
+            return false;
+        }
+
+        let last = self.last_alert.get(device_id).copied().unwrap_or(0);
+        let escalated = self
+            .alerted_severity
+            .get(device_id)
+            .is_some_and(|alerted| severity > *alerted);
+        if now_ts - last >= cooldown as i64 || escalated {
+            self.last_alert.insert(device_id.to_string(), now_ts);
+            self.is_failed
+                .insert(device_id.to_string(), currently_failed);
+            self.alerted_severity
+                .insert(device_id.to_string(), severity);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Mark device as recovered, return the outage summary if a failure alert had been sent
+    fn mark_recovered(&mut self, device_id: &str, now_ts: i64) -> Option<Recovery> {
+        let first_failure_ts = self.first_failure.remove(device_id);
+        let failures = self.last_failures.remove(device_id).unwrap_or_default();
+        self.alerted_severity.remove(device_id);
+        // A new outage after the recovery notice must alert at once, not wait out the cooldown
+        self.last_alert.remove(device_id);
+
+        self.is_failed.remove(device_id)?;
+        Some(Recovery {
+            first_failure_ts: first_failure_ts.unwrap_or(now_ts),
+            recovered_ts: now_ts,
+            failures,
+        })
+    }
+}
+
+// ────────────────────────────────────────────────────────────
+// Core Detection Logic (Three-level Concurrency + Semaphore Rate Limiting + Resource Reuse)
+// ────────────────────────────────────────────────────────────
+
+/// Returns the TCP handshake latency, or why the connection failed
+async fn check_port_with_semaphore(
+    ip: &str,
+    port: u16,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<Duration, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let start = Instant::now();
+    connect_tcp(ip, port, timeout_sec).await?;
+    Ok(start.elapsed())
+}
+
+/// Open a TCP connection within the timeout, errors already worded for alerts
+async fn connect_tcp(ip: &str, port: u16, timeout_sec: u64) -> Result<TcpStream, String> {
+    let addr = parse_ip(ip, port)?;
+    match timeout(Duration::from_secs(timeout_sec), TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("connect timed out after {}s", timeout_sec)),
+    }
+}
+
+/// Returns the time until the expected banner arrived; a port held by the wrong process
+/// or a wedged daemon fails with what was received so far
+async fn check_banner_with_semaphore(
+    banner: &BannerCheck,
+    ip: &str,
+    port: u16,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<Duration, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let pattern = banner.expect.as_deref().unwrap_or_default();
+    let expect = regex::bytes::Regex::new(pattern).map_err(|e| e.to_string())?;
+    let wait = Duration::from_millis(banner.expect_within_ms.unwrap_or(timeout_sec * 1000));
+
+    let start = Instant::now();
+    let mut stream = connect_tcp(ip, port, timeout_sec).await?;
+    if let Some(payload) = &banner.send {
+        stream
+            .write_all(payload.as_bytes())
+            .await
+            .map_err(|e| format!("send failed: {}", e))?;
+    }
+
+    let mut received = Vec::new();
+    let read_until_match = async {
+        let mut buf = [0u8; 4096];
+        while !expect.is_match(&received) {
+            if received.len() >= 64 * 1024 {
+                return Err("no match in first 64KiB");
+            }
+            match stream.read(&mut buf).await {
+                Ok(0) => return Err("connection closed"),
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(_) => return Err("connection reset"),
+            }
+        }
+        Ok(())
+    };
+
+    match timeout(wait, read_until_match).await {
+        Ok(Ok(())) => Ok(start.elapsed()),
+        Ok(Err(why)) => Err(format!(
+            "{} before /{}/ matched, received: {}",
+            why,
+            pattern,
+            banner_preview(&received)
+        )),
+        Err(_) => Err(format!(
+            "no /{}/ within {}ms, received: {}",
+            pattern,
+            wait.as_millis(),
+            banner_preview(&received)
+        )),
+    }
+}
+
+/// Returns the round trip time of AUTH (optional) + PING + role check (optional)
+async fn check_redis_with_semaphore(
+    check: &CheckItem,
+    ip: &str,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<Duration, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let start = Instant::now();
+
+    let session = async {
+        let mut conn = BufReader::new(connect_tcp(ip, check.port, timeout_sec).await?);
+
+        if let Some(password) = &check.auth.password {
+            let mut args = vec!["AUTH"];
+            args.extend(check.auth.username.as_deref());
+            args.push(password);
+            redis_command(&mut conn, &args)
+                .await
+                .map_err(|e| format!("AUTH failed: {}", e))?;
+        }
+
+        let pong = redis_command(&mut conn, &["PING"])
+            .await
+            .map_err(|e| format!("PING failed: {}", e))?;
+        if pong != "PONG" {
+            return Err(format!("PING answered {:?}", pong));
+        }
+
+        if let Some(expected) = &check.redis.role {
+            let info = redis_command(&mut conn, &["INFO", "replication"])
+                .await
+                .map_err(|e| format!("INFO replication failed: {}", e))?;
+            let role = info
+                .lines()
+                .find_map(|line| line.strip_prefix("role:"))
+                .map(str::trim)
+                .unwrap_or("unknown");
+            // Redis still reports replicas as "slave"
+            let actual = if role == "slave" { "replica" } else { role };
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(format!("role is {}, expected {}", actual, expected));
+            }
+        }
+        Ok(())
+    };
+
+    timeout(Duration::from_secs(timeout_sec), session)
+        .await
+        .map_err(|_| format!("no reply within {}s", timeout_sec))??;
+    Ok(start.elapsed())
+}
+
+/// Minimal RESP client: send one command, read one simple/bulk string reply.
+/// A Redis error reply (`-ERR ...`, `-NOAUTH ...`, `-LOADING ...`) becomes `Err` with its text.
+async fn redis_command(conn: &mut BufReader<TcpStream>, args: &[&str]) -> Result<String, String> {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    conn.write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut line = String::new();
+    if conn.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+        return Err("connection closed".to_string());
+    }
+    let line = line.trim_end();
+    match line.split_at(line.len().min(1)) {
+        ("+", text) | (":", text) => Ok(text.to_string()),
+        ("-", error) => Err(error.to_string()),
+        ("$", len) => {
+            let len: usize = len
+                .parse()
+                .map_err(|_| format!("malformed reply {:?}", line))?;
+            let mut payload = vec![0u8; len + 2];
+            conn.read_exact(&mut payload)
+                .await
+                .map_err(|e| e.to_string())?;
+            payload.truncate(len);
+            Ok(String::from_utf8_lossy(&payload).into_owned())
+        }
+        _ => Err(format!("not a Redis reply: {:?}", line)),
+    }
+}
+
+/// Reads the MySQL initial handshake packet and returns the server version. With a username,
+/// logs in without password and quits cleanly, so probes don't count against
+/// `max_connect_errors` and get the monitoring host blocked.
+async fn check_mysql_with_semaphore(
+    check: &CheckItem,
+    ip: &str,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<ProbeOk, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let start = Instant::now();
+
+    let session = async {
+        let mut conn = connect_tcp(ip, check.port, timeout_sec).await?;
+        let (_, greeting) = mysql_read_packet(&mut conn)
+            .await
+            .map_err(|e| format!("no greeting: {}", e))?;
+        let version = match greeting.first() {
+            Some(0x0a) => {
+                let end = greeting[1..]
+                    .iter()
+                    .position(|b| *b == 0)
+                    .ok_or("malformed greeting")?;
+                String::from_utf8_lossy(&greeting[1..1 + end]).into_owned()
+            }
+            Some(0xff) => return Err(mysql_error(&greeting)),
+            _ => {
+                return Err(format!(
+                    "not a MySQL greeting: {}",
+                    banner_preview(&greeting)
+                ))
+            }
+        };
+
+        if let Some(user) = &check.auth.username {
+            // HandshakeResponse41: LONG_PASSWORD | PROTOCOL_41 | TRANSACTIONS | SECURE_CONNECTION
+            let mut login = Vec::new();
+            login.extend_from_slice(&(0x0000_a201u32).to_le_bytes());
+            login.extend_from_slice(&(16u32 << 20).to_le_bytes());
+            login.push(33); // utf8_general_ci
+            login.extend_from_slice(&[0u8; 23]);
+            login.extend_from_slice(user.as_bytes());
+            login.push(0);
+            login.push(0); // empty auth response
+            mysql_write_packet(&mut conn, 1, &login).await?;
+
+            let (_, reply) = mysql_read_packet(&mut conn)
+                .await
+                .map_err(|e| format!("no login reply: {}", e))?;
+            match reply.first() {
+                Some(0x00) => mysql_write_packet(&mut conn, 0, &[0x01]).await?, // COM_QUIT
+                Some(0xff) => return Err(mysql_error(&reply)),
+                _ => {
+                    return Err(format!(
+                        "login as '{}' needs a password, use a password-less monitoring account",
+                        user
+                    ))
+                }
+            }
+        }
+        Ok(version)
+    };
+
+    let version = timeout(Duration::from_secs(timeout_sec), session)
+        .await
+        .map_err(|_| format!("no handshake within {}s", timeout_sec))??;
+    Ok(ProbeOk {
+        latency: start.elapsed(),
+        warning: None,
+        version: Some(format!("MySQL {}", version)),
+        packet_loss: None,
+    })
+}
+
+/// One MySQL packet: 3-byte little-endian length, sequence id, payload
+async fn mysql_read_packet(conn: &mut TcpStream) -> Result<(u8, Vec<u8>), String> {
+    let mut header = [0u8; 4];
+    conn.read_exact(&mut header).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            "connection closed".to_string()
+        } else {
+            e.to_string()
+        }
+    })?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+    // Greetings and login replies are small; a huge length means another protocol answered
+    if len > 0xffff {
+        return Err(format!("not a MySQL server (packet length {})", len));
+    }
+    let mut payload = vec![0u8; len];
+    conn.read_exact(&mut payload)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((header[3], payload))
+}
+
+async fn mysql_write_packet(conn: &mut TcpStream, seq: u8, payload: &[u8]) -> Result<(), String> {
+    let len = (payload.len() as u32).to_le_bytes();
+    let mut packet = vec![len[0], len[1], len[2], seq];
+    packet.extend_from_slice(payload);
+    conn.write_all(&packet).await.map_err(|e| e.to_string())
+}
+
+/// ERR packet: 0xff, error code, optional `#` + SQLSTATE, message
+fn mysql_error(payload: &[u8]) -> String {
+    if payload.len() < 3 {
+        return "malformed error packet".to_string();
+    }
+    let code = u16::from_le_bytes([payload[1], payload[2]]);
+    let mut message = &payload[3..];
+    if message.first() == Some(&b'#') && message.len() >= 6 {
+        message = &message[6..];
+    }
+    format!("MySQL error {}: {}", code, String::from_utf8_lossy(message))
+}
+
+/// SSLRequest, then a protocol 3.0 StartupMessage. Any authentication request or an auth/role
+/// error (SQLSTATE class 28, 3D) proves the server accepts connections; "starting up",
+/// "in recovery", "shutting down" (class 57) and "too many clients" (class 53) count as down.
+async fn check_postgres_with_semaphore(
+    check: &CheckItem,
+    ip: &str,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<ProbeOk, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let user = check.auth.username.as_deref().unwrap_or("port_sentinel");
+    let database = check.postgres.database.as_deref().unwrap_or(user);
+    let start = Instant::now();
+
+    let session = async {
+        let mut conn = connect_tcp(ip, check.port, timeout_sec).await?;
+        conn.write_all(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f])
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut answer = [0u8; 1];
+        conn.read_exact(&mut answer)
+            .await
+            .map_err(|_| "connection closed before SSLRequest reply".to_string())?;
+        match answer[0] {
+            // The StartupMessage can't follow a TLS upgrade in plain text, so use a new connection
+            b'S' => conn = connect_tcp(ip, check.port, timeout_sec).await?,
+            b'N' => {}
+            b'E' => return Err("server rejected SSLRequest (pre-7.0 protocol)".to_string()),
+            other => return Err(format!("not a PostgreSQL server (answered {:#04x})", other)),
+        }
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&196608u32.to_be_bytes()); // protocol 3.0
+        for (key, value) in [
+            ("user", user),
+            ("database", database),
+            ("application_name", "port-sentinel"),
+        ] {
+            body.extend_from_slice(key.as_bytes());
+            body.push(0);
+            body.extend_from_slice(value.as_bytes());
+            body.push(0);
+        }
+        body.push(0);
+        let mut startup = ((body.len() + 4) as u32).to_be_bytes().to_vec();
+        startup.extend_from_slice(&body);
+        conn.write_all(&startup).await.map_err(|e| e.to_string())?;
+
+        let mut version = None;
+        loop {
+            let (tag, payload) = postgres_read_message(&mut conn).await?;
+            match tag {
+                b'R' if payload.get(..4) == Some(&[0, 0, 0, 0]) => {} // AuthenticationOk (trust)
+                b'R' => return Ok(version), // password/SASL requested: server is up
+                b'S' => {
+                    let mut fields = payload.split(|b| *b == 0);
+                    if fields.next() == Some(b"server_version") {
+                        version = fields
+                            .next()
+                            .map(|v| format!("PostgreSQL {}", String::from_utf8_lossy(v)));
+                    }
+                }
+                b'Z' => {
+                    conn.write_all(&[b'X', 0, 0, 0, 4]).await.ok(); // Terminate
+                    return Ok(version);
+                }
+                b'E' => {
+                    let (code, message) = postgres_error(&payload);
+                    return match code.get(..2) {
+                        Some("57") | Some("53") => Err(format!("{} ({})", message, code)),
+                        _ => Ok(version),
+                    };
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let version = timeout(Duration::from_secs(timeout_sec), session)
+        .await
+        .map_err(|_| format!("no startup reply within {}s", timeout_sec))??;
+    Ok(ProbeOk {
+        latency: start.elapsed(),
+        warning: None,
+        version,
+        packet_loss: None,
+    })
+}
+
+/// One backend message: type byte, 4-byte big-endian length (including itself), payload
+async fn postgres_read_message(conn: &mut TcpStream) -> Result<(u8, Vec<u8>), String> {
+    let mut header = [0u8; 5];
+    conn.read_exact(&mut header)
+        .await
+        .map_err(|_| "connection closed during startup".to_string())?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if !(4..=1 << 20).contains(&len) {
+        return Err(format!("not a PostgreSQL server (message length {})", len));
+    }
+    let mut payload = vec![0u8; len - 4];
+    conn.read_exact(&mut payload)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((header[0], payload))
+}
+
+/// SQLSTATE (`C` field) and message (`M` field) of an ErrorResponse
+fn postgres_error(payload: &[u8]) -> (String, String) {
+    let (mut code, mut message) = (String::new(), String::new());
+    for field in payload.split(|b| *b == 0).filter(|f| !f.is_empty()) {
+        let value = String::from_utf8_lossy(&field[1..]).into_owned();
+        match field[0] {
+            b'C' => code = value,
+            b'M' => message = value,
+            _ => {}
+        }
+    }
+    (code, message)
+}
+
+/// Sends one datagram (DNS query, NTP client request or raw payload) and returns the time until
+/// a valid reply arrives. An ICMP port unreachable surfaces as "connection refused".
+async fn check_udp_with_semaphore(
+    check: &CheckItem,
+    ip: &str,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<Duration, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let mode = check.udp.mode();
+    let (request, dns_id) = match mode {
+        UdpMode::Dns => {
+            let id = (Local::now().timestamp_subsec_nanos() & 0xffff) as u16;
+            (dns_query(id, check.udp.query.as_deref().unwrap_or(".")), id)
+        }
+        UdpMode::Ntp => {
+            let mut packet = vec![0u8; 48];
+            packet[0] = 0x23; // LI 0, version 4, mode 3 (client)
+            (packet, 0)
+        }
+        UdpMode::Raw => (
+            check.banner.send.clone().unwrap_or_default().into_bytes(),
+            0,
+        ),
+    };
+
+    let addr = parse_ip(ip, check.port)?;
+    let bind = if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = tokio::net::UdpSocket::bind(bind)
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.connect(addr).await.map_err(|e| e.to_string())?;
+
+    let start = Instant::now();
+    let exchange = async {
+        socket.send(&request).await?;
+        let mut buf = vec![0u8; 4096];
+        // An ICMP port unreachable only raises the error readiness, so wait for that too
+        loop {
+            let ready = socket
+                .ready(tokio::io::Interest::READABLE | tokio::io::Interest::ERROR)
+                .await?;
+            if ready.is_error() {
+                let error = socket.take_error()?;
+                return Err(error.unwrap_or_else(|| std::io::Error::other("socket error")));
+            }
+            match socket.try_recv(&mut buf) {
+                Ok(n) => {
+                    buf.truncate(n);
+                    return Ok(buf);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    let reply = timeout(Duration::from_secs(timeout_sec), exchange)
+        .await
+        .map_err(|_| format!("no {} reply within {}s", mode.as_str(), timeout_sec))?
+        .map_err(|e| e.to_string())?;
+    let latency = start.elapsed();
+
+    match mode {
+        UdpMode::Dns => {
+            if reply.len() < 12 || u16::from_be_bytes([reply[0], reply[1]]) != dns_id {
+                return Err("not a DNS reply (id mismatch)".to_string());
+            }
+            // NOERROR / NXDOMAIN mean the server answers; SERVFAIL, REFUSED etc. don't
+            match reply[3] & 0x0f {
+                0 | 3 => {}
+                2 => return Err("DNS SERVFAIL".to_string()),
+                5 => return Err("DNS REFUSED".to_string()),
+                rcode => return Err(format!("DNS rcode {}", rcode)),
+            }
+        }
+        UdpMode::Ntp => {
+            if reply.len() < 48 || reply[0] & 0x07 != 4 {
+                return Err("not an NTP server reply".to_string());
+            }
+            let stratum = reply[1];
+            if reply[0] >> 6 == 3 || stratum == 0 || stratum >= 16 {
+                return Err(format!("NTP server unsynchronized (stratum {})", stratum));
+            }
+        }
+        UdpMode::Raw => {
+            if let Some(pattern) = &check.banner.expect {
+                let expect = regex::bytes::Regex::new(pattern).map_err(|e| e.to_string())?;
+                if !expect.is_match(&reply) {
+                    return Err(format!(
+                        "reply does not match /{}/, received: {}",
+                        pattern,
+                        banner_preview(&reply)
+                    ));
+                }
+            }
+        }
+    }
+    Ok(latency)
+}
+
+/// Recursive A query for `name` ("." for the root zone)
+fn dns_query(id: u16, name: &str) -> Vec<u8> {
+    let mut packet = id.to_be_bytes().to_vec();
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]); // RD, 1 question
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&[0, 1, 0, 1]); // QTYPE A, QCLASS IN
+    packet
+}
+
+/// Gap between two echo requests of one ICMP check
+const ICMP_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Pings the IP `count` times and returns the average RTT. Fails on 100% packet loss, warns
+/// when loss or RTT cross `max_loss` / `max_rtt_ms`.
+async fn check_icmp_with_semaphore(
+    icmp: &IcmpCheck,
+    ip: &str,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<ProbeOk, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let addr = parse_ip(ip, 0)?;
+    let (socket, raw) = icmp_socket(addr.is_ipv6())?;
+    // Connected sockets only see replies from this address
+    socket.connect(&addr.into()).map_err(|e| e.to_string())?;
+    let socket = AsyncFd::new(socket).map_err(|e| e.to_string())?;
+
+    let count = icmp.count();
+    // Ping sockets get their id assigned by the kernel, raw sockets need one to filter replies
+    let id = (std::process::id() as u16) ^ (Local::now().timestamp_subsec_nanos() as u16);
+    let mut sent: Vec<Option<Instant>> = vec![None; count as usize];
+    let mut rtts = Vec::new();
+    let start = Instant::now();
+    let deadline = start + ICMP_INTERVAL * (count - 1) + Duration::from_secs(timeout_sec);
+    let mut next_send = start;
+    let mut seq = 0u16;
+    let mut buf = [0u8; 1500];
+
+    while rtts.len() < count as usize {
+        let now = Instant::now();
+        if u32::from(seq) < count && now >= next_send {
+            let request = icmp_echo_request(addr.is_ipv6(), id, seq);
+            socket.get_ref().send(&request).map_err(|e| e.to_string())?;
+            sent[seq as usize] = Some(now);
+            seq += 1;
+            next_send += ICMP_INTERVAL;
+            continue;
+        }
+        if now >= deadline {
+            break;
+        }
+        let wake = if u32::from(seq) < count {
+            next_send.min(deadline)
+        } else {
+            deadline
+        };
+        let Ok(ready) = tokio::time::timeout_at(wake.into(), socket.readable()).await else {
+            continue;
+        };
+        let mut guard = ready.map_err(|e| e.to_string())?;
+        let n = match guard.try_io(|fd| std::io::Read::read(&mut fd.get_ref(), &mut buf)) {
+            Ok(result) => result.map_err(|e| e.to_string())?,
+            Err(_would_block) => continue,
+        };
+        if let Some(reply_seq) = icmp_echo_reply_seq(&buf[..n], addr.is_ipv6(), raw, id) {
+            if let Some(sent_at) = sent.get_mut(reply_seq as usize).and_then(Option::take) {
+                rtts.push(sent_at.elapsed());
+            }
+        }
+    }
+
+    if rtts.is_empty() {
+        return Err(format!("100% packet loss ({} sent)", count));
+    }
+    let loss = 1.0 - rtts.len() as f64 / count as f64;
+    let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+    let max = rtts.iter().max().copied().unwrap_or_default();
+    let over_loss = icmp
+        .max_loss
+        .is_some_and(|limit| loss * 100.0 > f64::from(limit));
+    let over_rtt = icmp
+        .max_rtt_ms
+        .is_some_and(|limit| avg > Duration::from_millis(limit));
+    let warning = (over_loss || over_rtt).then(|| {
+        format!(
+            "{:.0}% packet loss ({}/{} lost), rtt avg {:.1}ms max {:.1}ms",
+            loss * 100.0,
+            count as usize - rtts.len(),
+            count,
+            avg.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0
+        )
+    });
+    Ok(ProbeOk {
+        latency: avg,
+        warning,
+        version: None,
+        packet_loss: Some(loss),
+    })
+}
+
+/// Unprivileged ping socket when `net.ipv4.ping_group_range` covers our group, raw socket
+/// (root / CAP_NET_RAW) otherwise. The flag tells whether the socket is raw.
+fn icmp_socket(v6: bool) -> Result<(Socket, bool), String> {
+    let (domain, protocol) = if v6 {
+        (Domain::IPV6, socket2::Protocol::ICMPV6)
+    } else {
+        (Domain::IPV4, socket2::Protocol::ICMPV4)
+    };
+    let (socket, raw) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => (socket, false),
+        Err(_) => match Socket::new(domain, Type::RAW, Some(protocol)) {
+            Ok(socket) => (socket, true),
+            Err(e) => {
+                return Err(format!(
+                    "can't open ICMP socket: {} (allow our group in net.ipv4.ping_group_range \
+                     or grant CAP_NET_RAW)",
+                    e
+                ))
+            }
+        },
+    };
+    socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+    Ok((socket, raw))
+}
+
+/// Echo request with a fixed payload; ICMPv6 checksums are filled in by the kernel
+fn icmp_echo_request(v6: bool, id: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![if v6 { 128 } else { 8 }, 0, 0, 0];
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(b"port-sentinel-rs");
+    if !v6 {
+        let checksum = internet_checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// Sequence number of an echo reply to us, `None` for anything else a raw socket sees
+fn icmp_echo_reply_seq(packet: &[u8], v6: bool, raw: bool, id: u16) -> Option<u16> {
+    // Raw IPv4 sockets deliver the IP header too
+    let icmp = if raw && !v6 {
+        packet.get(usize::from(packet.first()? & 0x0f) * 4..)?
+    } else {
+        packet
+    };
+    let reply_type = if v6 { 129 } else { 0 };
+    if icmp.len() < 8 || icmp[0] != reply_type {
+        return None;
+    }
+    if raw && u16::from_be_bytes([icmp[4], icmp[5]]) != id {
+        return None;
+    }
+    Some(u16::from_be_bytes([icmp[6], icmp[7]]))
+}
+
+/// RFC 1071 one's complement sum
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// First line of received bytes for alert text, e.g. `"SSH-2.0-OpenSSH_8.9"`
+fn banner_preview(received: &[u8]) -> String {
+    let text = String::from_utf8_lossy(received);
+    let line = text.trim().lines().next().unwrap_or_default();
+    if line.is_empty() {
+        return "nothing".to_string();
+    }
+    format!("{:?}", line.chars().take(80).collect::<String>())
+}
+
+/// Returns the HTTP response latency once status and body assertions pass
+async fn check_http_with_semaphore(
+    check: &CheckItem,
+    ip: &str,
+    port: u16,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<Duration, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let http = &check.http;
+    let scheme = if http.https { "https" } else { "http" };
+    let addr = parse_ip(ip, port)?;
+    // URLs can't carry IPv6 zones: route a link-local address through a pinned placeholder name
+    let (host, pinned) = match (&http.host, addr) {
+        (Some(host), _) => (host.clone(), Some(host.as_str())),
+        (None, SocketAddr::V6(v6)) if v6.scope_id() != 0 => {
+            ("link-local.invalid".to_string(), Some("link-local.invalid"))
+        }
+        (None, SocketAddr::V6(v6)) => (format!("[{}]", v6.ip()), None),
+        (None, SocketAddr::V4(v4)) => (v4.ip().to_string(), None),
+    };
+    let url = format!("{}://{}:{}{}", scheme, host, port, http.path());
+
+    let redirect = if http.follow_redirects {
+        reqwest::redirect::Policy::limited(10)
+    } else {
+        reqwest::redirect::Policy::none()
+    };
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(timeout_sec))
+        .redirect(redirect)
+        .danger_accept_invalid_certs(check.insecure);
+    if let Some(host) = pinned {
+        // Pin the virtual host to the probed IP so Host header and SNI stay intact
+        builder = builder.resolve(host, addr);
+    }
+    let client = builder.build().map_err(|e| e.to_string())?;
+    let method =
+        reqwest::Method::from_bytes(http.method().as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut request = client.request(method, &url);
+    if http.host.is_none() && pinned.is_some() {
+        request = request.header(reqwest::header::HOST, format!("[{}]:{}", addr.ip(), port));
+    }
+    for (key, value) in &http.headers {
+        request = request.header(key, value);
+    }
+
+    let start = Instant::now();
+    let response = request.send().await.map_err(|e| {
+        if e.is_timeout() {
+            format!("request timed out after {}s", timeout_sec)
+        } else {
+            root_cause(&e)
+        }
+    })?;
+
+    let status = response.status().as_u16();
+    if !http.status_ok(status) {
+        let expected = if http.expect_status.is_empty() {
+            "2xx/3xx".to_string()
+        } else {
+            http.expect_status
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+        return Err(format!("HTTP {} (expected {})", status, expected));
+    }
+
+    if http.body_contains.is_some() || http.body_regex.is_some() {
+        let body = response
+            .text()
+            .await
+            .map_err(|e| format!("reading body failed: {}", e.without_url()))?;
+        if let Some(needle) = &http.body_contains {
+            if !body.contains(needle.as_str()) {
+                return Err(format!(
+                    "HTTP {}, body does not contain {:?}",
+                    status, needle
+                ));
+            }
+        }
+        if let Some(pattern) = &http.body_regex {
+            let re = Regex::new(pattern).map_err(|e| e.to_string())?;
+            if !re.is_match(&body) {
+                return Err(format!(
+                    "HTTP {}, body does not match /{}/",
+                    status, pattern
+                ));
+            }
+        }
+    }
+
+    Ok(start.elapsed())
+}
+
+/// Returns the TLS handshake latency once the chain validates; a certificate close to expiry
+/// passes with a warning, an expired one fails, both carrying the expiry date and issuer
+async fn check_tls_with_semaphore(
+    check: &CheckItem,
+    ip: &str,
+    port: u16,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<ProbeOk, String> {
+    let _permit = semaphore.acquire().await.unwrap();
+    let tls = &check.tls;
+    let addr = parse_ip(ip, port)?;
+    let server_name = match &tls.sni {
+        Some(sni) => {
+            ServerName::try_from(sni.clone()).map_err(|e| format!("invalid SNI: {}", e))?
+        }
+        None => ServerName::from(addr.ip()),
+    };
+    let connector = TlsConnector::from(tls_client_config(tls.ca_file.as_deref(), check.insecure)?);
+
+    let start = Instant::now();
+    let handshake = async {
+        let stream = TcpStream::connect(addr).await?;
+        connector.connect(server_name, stream).await
+    };
+    let stream = timeout(Duration::from_secs(timeout_sec), handshake)
+        .await
+        .map_err(|_| format!("TLS handshake timed out after {}s", timeout_sec))?
+        .map_err(|e| root_cause(&e))?;
+    let latency = start.elapsed();
+
+    let (_, conn) = stream.get_ref();
+    let leaf = conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .ok_or("server sent no certificate")?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf.as_ref())
+        .map_err(|e| format!("unparsable certificate: {}", e))?;
+
+    let not_after = cert.validity().not_after.timestamp();
+    let now = Local::now().timestamp();
+    if not_after < now {
+        return Err(format!(
+            "certificate expired {} days ago ({}), issuer: {}",
+            (now - not_after) / 86400,
+            format_ts(not_after),
+            cert.issuer()
+        ));
+    }
+    let days_left = (not_after - now) / 86400;
+    let warning = (days_left < tls.expiry_warn_days() as i64).then(|| {
+        format!(
+            "certificate expires in {} days ({}), issuer: {}",
+            days_left,
+            format_ts(not_after),
+            cert.issuer()
+        )
+    });
+    Ok(ProbeOk {
+        latency,
+        warning,
+        version: None,
+        packet_loss: None,
+    })
+}
+
+/// Client config of tls checks: Mozilla roots plus `ca_file`, safe default protocol versions.
+/// The common case (no `ca_file`, not insecure) is built once and shared.
+fn tls_client_config(
+    ca_file: Option<&str>,
+    insecure: bool,
+) -> Result<Arc<rustls::ClientConfig>, String> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    if ca_file.is_none() && !insecure {
+        return Ok(CONFIG
+            .get_or_init(|| {
+                Arc::new(build_tls_config(None, false).expect("Mozilla roots build a verifier"))
+            })
+            .clone());
+    }
+    build_tls_config(ca_file, insecure).map(Arc::new)
+}
+
+fn build_tls_config(ca_file: Option<&str>, insecure: bool) -> Result<rustls::ClientConfig, String> {
+    let mut roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    if let Some(path) = ca_file {
+        for cert in load_ca_file(path)? {
+            roots
+                .add(cert)
+                .map_err(|e| format!("ca_file {}: {}", path, e))?;
+        }
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let inner = rustls::client::WebPkiServerVerifier::builder_with_provider(
+        Arc::new(roots),
+        provider.clone(),
+    )
+    .build()
+    .map_err(|e| e.to_string())?;
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("ring provider supports default TLS versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(ProbeCertVerifier { inner, insecure }))
+        .with_no_client_auth();
+    Ok(config)
+}
+
+/// Certificates of a PEM bundle, at least one
+fn load_ca_file(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = fs::read(path).map_err(|e| format!("ca_file {}: {}", path, e))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("ca_file {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("ca_file {}: no PEM certificates found", path));
+    }
+    Ok(certs)
+}
+
+/// WebPKI verification, except that an expired chain is verified again as of the leaf's
+/// expiry: the handshake completes and the probe reports the date and issuer itself.
+/// `insecure` skips chain and name checks but still verifies handshake signatures.
+#[derive(Debug)]
+struct ProbeCertVerifier {
+    inner: Arc<rustls::client::WebPkiServerVerifier>,
+    insecure: bool,
+}
+
+impl ServerCertVerifier for ProbeCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.insecure {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        match verified {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::Expired | rustls::CertificateError::ExpiredContext { .. },
+            )) => {
+                let (_, cert) =
+                    x509_parser::parse_x509_certificate(end_entity.as_ref()).map_err(|_| {
+                        rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
+                    })?;
+                let not_after = cert.validity().not_after.timestamp().max(1) as u64;
+                let at = UnixTime::since_unix_epoch(Duration::from_secs(not_after - 1));
+                self.inner.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    at,
+                )
+            }
+            other => other,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Innermost error message, e.g. "Connection refused" instead of reqwest's wrapper chain
+fn root_cause(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut cause = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+/// One address to probe: an IP listed in `Device.ips`, or one a listed hostname resolved to
+#[derive(Clone)]
+struct Target {
+    addr: String,
+    host: Option<String>,
+}
+
+impl Target {
+    /// "10.0.0.5", or "db.example.com (10.0.0.5)" for resolved hostnames
+    fn label(&self) -> String {
+        match &self.host {
+            Some(host) => format!("{} ({})", host, self.addr),
+            None => self.addr.clone(),
+        }
+    }
+}
+
+/// Parse an IP literal from `Device.ips` into a socket address. IPv6 may carry a zone
+/// (`fe80::1%eth0` or `fe80::1%2`), which link-local addresses need to be routable.
+fn parse_ip(ip: &str, port: u16) -> Result<SocketAddr, String> {
+    let (addr, zone) = match ip.split_once('%') {
+        Some((addr, zone)) => (addr, Some(zone)),
+        None => (ip, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("invalid IP address '{}'", ip))?;
+    match (addr, zone) {
+        (IpAddr::V6(v6), Some(zone)) => Ok(SocketAddr::V6(SocketAddrV6::new(
+            v6,
+            port,
+            0,
+            interface_index(zone)?,
+        ))),
+        (IpAddr::V4(_), Some(_)) => Err(format!("zone id is only valid for IPv6: '{}'", ip)),
+        (addr, None) => Ok(SocketAddr::new(addr, port)),
+    }
+}
+
+/// Interface index of a zone id, given as a number or an interface name
+fn interface_index(zone: &str) -> Result<u32, String> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+    let name = std::ffi::CString::new(zone).map_err(|_| format!("invalid zone '{}'", zone))?;
+    // SAFETY: `name` is a valid NUL-terminated string that outlives the call
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(format!("unknown network interface '{}'", zone)),
+        index => Ok(index),
+    }
+}
+
+/// Inverse of `parse_ip`: the address without port, zone kept as its numeric index
+fn format_ip(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(v6) if v6.scope_id() != 0 => format!("{}%{}", v6.ip(), v6.scope_id()),
+        _ => addr.ip().to_string(),
+    }
+}
+
+/// Resolve `Device.ips` for this round: IPs pass through, hostnames expand to every A/AAAA
+/// record. Hostnames that fail to resolve come back as (hostname, reason).
+async fn resolve_targets(
+    entries: &[String],
+    timeout_sec: u64,
+) -> (Vec<Target>, Vec<(String, String)>) {
+    let mut targets = Vec::new();
+    let mut failures = Vec::new();
+    for entry in entries {
+        if parse_ip(entry, 0).is_ok() {
+            targets.push(Target {
+                addr: entry.clone(),
+                host: None,
+            });
+            continue;
+        }
+        let lookup = tokio::net::lookup_host((entry.as_str(), 0));
+        let addrs = match timeout(Duration::from_secs(timeout_sec), lookup).await {
+            Ok(Ok(addrs)) => addrs.collect::<Vec<_>>(),
+            Ok(Err(e)) => {
+                failures.push((entry.clone(), format!("DNS lookup failed: {}", e)));
+                continue;
+            }
+            Err(_) => {
+                failures.push((
+                    entry.clone(),
+                    format!("DNS lookup timed out after {}s", timeout_sec),
+                ));
+                continue;
+            }
+        };
+        if addrs.is_empty() {
+            failures.push((entry.clone(), "DNS lookup returned no address".to_string()));
+        }
+        let mut seen = HashSet::new();
+        for addr in addrs.into_iter().filter(|a| seen.insert(*a)) {
+            targets.push(Target {
+                addr: format_ip(&addr),
+                host: Some(entry.clone()),
+            });
+        }
+    }
+    (targets, failures)
+}
+
+/// Run one check against one address according to its type
+async fn probe_target(
+    check: &CheckItem,
+    target: &Target,
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Result<ProbeOk, String> {
+    let ip = target.addr.as_str();
+    let port = check.port;
+    // A hostname target keeps its name for the Host header / SNI, unless set explicitly
+    let mut check = std::borrow::Cow::Borrowed(check);
+    if let Some(host) = &target.host {
+        if check.kind == CheckKind::Http && check.http.host.is_none() {
+            check.to_mut().http.host = Some(host.clone());
+        }
+        if check.kind == CheckKind::Tls && check.tls.sni.is_none() {
+            check.to_mut().tls.sni = Some(host.clone());
+        }
+    }
+    let check = check.as_ref();
+    if check.protocol == Protocol::Udp {
+        return check_udp_with_semaphore(check, ip, timeout_sec, semaphore)
+            .await
+            .map(ProbeOk::from);
+    }
+    match check.kind {
+        CheckKind::Tcp => check_port_with_semaphore(ip, port, timeout_sec, semaphore)
+            .await
+            .map(ProbeOk::from),
+        CheckKind::Http => check_http_with_semaphore(check, ip, port, timeout_sec, semaphore)
+            .await
+            .map(ProbeOk::from),
+        CheckKind::Tls => check_tls_with_semaphore(check, ip, port, timeout_sec, semaphore).await,
+        CheckKind::Banner => {
+            check_banner_with_semaphore(&check.banner, ip, port, timeout_sec, semaphore)
+                .await
+                .map(ProbeOk::from)
+        }
+        CheckKind::Redis => check_redis_with_semaphore(check, ip, timeout_sec, semaphore)
+            .await
+            .map(ProbeOk::from),
+        CheckKind::Mysql => check_mysql_with_semaphore(check, ip, timeout_sec, semaphore).await,
+        CheckKind::Postgres => {
+            check_postgres_with_semaphore(check, ip, timeout_sec, semaphore).await
+        }
+        CheckKind::Icmp => check_icmp_with_semaphore(&check.icmp, ip, timeout_sec, semaphore).await,
+    }
+}
+
+/// A passing probe, possibly with a warning (e.g. certificate close to expiry)
+#[derive(Clone)]
+struct ProbeOk {
+    latency: Duration,
+    warning: Option<String>,
+    /// Server version announced during the handshake (MySQL greeting, PostgreSQL server_version)
+    version: Option<String>,
+    /// Fraction of lost echo requests (icmp checks)
+    packet_loss: Option<f64>,
+}
+
+impl From<Duration> for ProbeOk {
+    fn from(latency: Duration) -> Self {
+        Self {
+            latency,
+            warning: None,
+            version: None,
+            packet_loss: None,
+        }
+    }
+}
+
+/// Outcome of one check against one address: the passing probe, or the failure reason
+#[derive(Clone)]
+struct ProbeResult {
+    /// `Target::label()`, or the bare hostname when it did not resolve
+    ip: String,
+    outcome: Result<ProbeOk, String>,
+    kind: FailureKind,
+}
+
+/// Why a probe failed: the check itself, or resolving the hostname it should run against
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FailureKind {
+    #[default]
+    Probe,
+    Dns,
+}
+
+/// Outcome of one check against all IPs of a device
+struct CheckResult {
+    /// Position of the check in `Device.checks`
+    index: usize,
+    check: CheckItem,
+    probes: Vec<ProbeResult>,
+    policy: IpPolicy,
+}
+
+impl CheckResult {
+    fn targets_up(&self) -> usize {
+        self.probes.iter().filter(|p| p.outcome.is_ok()).count()
+    }
+
+    /// Enough targets passed for the check's `ip_policy`
+    fn passed(&self) -> bool {
+        self.targets_up() >= self.policy.required(self.probes.len())
+    }
+
+    /// Error if too few targets passed; warning if it passed but some target reported a
+    /// warning, or a quorum check is degraded (some targets down)
+    fn failure(&self) -> Option<CheckFailure> {
+        let up = self.targets_up();
+        let total = self.probes.len();
+        let (severity, affected): (Severity, Vec<&ProbeResult>) = if self.passed() {
+            let degraded = matches!(self.policy, IpPolicy::Quorum(_)) && up < total;
+            let warned = self
+                .probes
+                .iter()
+                .filter(|p| match &p.outcome {
+                    Ok(ok) => ok.warning.is_some(),
+                    Err(_) => degraded,
+                })
+                .collect();
+            (Severity::Warning, warned)
+        } else {
+            let failed = self.probes.iter().filter(|p| p.outcome.is_err()).collect();
+            (Severity::Error, failed)
+        };
+        if affected.is_empty() {
+            return None;
+        }
+        let kind = if affected.iter().all(|p| p.kind == FailureKind::Dns) {
+            FailureKind::Dns
+        } else {
+            FailureKind::Probe
+        };
+        let affected: Vec<(&String, &String)> = affected
+            .iter()
+            .filter_map(|p| match &p.outcome {
+                Ok(ok) => ok.warning.as_ref().map(|warning| (&p.ip, warning)),
+                Err(reason) => Some((&p.ip, reason)),
+            })
+            .collect();
+
+        // One reason if all IPs agree, otherwise prefix each reason with its IP
+        let mut reason = match affected.as_slice() {
+            [(_, first), rest @ ..] if rest.iter().all(|(_, r)| r == first) => (*first).clone(),
+            _ => affected
+                .iter()
+                .map(|(ip, reason)| {
+                    // "fe80::1: refused" would read as part of the address
+                    if ip.contains(':') && !ip.contains(' ') {
+                        format!("[{}]: {}", ip, reason)
+                    } else {
+                        format!("{}: {}", ip, reason)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+        };
+        if up < total && total > 1 {
+            reason = format!("{}/{} targets up; {}", up, total, reason);
+        }
+
+        Some(CheckFailure {
+            check_name: self.check.display_name(),
+            port: self.check.port,
+            attempted_ips: affected.iter().map(|(ip, _)| (*ip).clone()).collect(),
+            reason,
+            severity,
+            kind,
+            targets_up: up,
+            targets_total: total,
+        })
+    }
+}
+
+async fn check_item_with_parallel_ip(
+    check: &CheckItem,
+    targets: &[Target],
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Vec<ProbeResult> {
+    let mut tasks = tokio::task::JoinSet::new();
+
+    for target in targets {
+        let target_clone = target.clone();
+        let sem_clone = semaphore.clone();
+        let check_clone = check.clone();
+        let to_sec = timeout_sec;
+
+        tasks.spawn(async move {
+            let outcome = probe_target(&check_clone, &target_clone, to_sec, sem_clone).await;
+            ProbeResult {
+                ip: target_clone.label(),
+                outcome,
+                kind: FailureKind::Probe,
+            }
+        });
+    }
+
+    let mut probes = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        if let Ok(probe) = result {
+            probes.push(probe);
+        }
+    }
+    probes
+}
+
+/// Run the checks at positions `due` of a device; `timeout_sec` is the global default timeout
+async fn check_device_parallel(
+    device: &Device,
+    due: &[usize],
+    timeout_sec: u64,
+    semaphore: Arc<Semaphore>,
+) -> Vec<CheckResult> {
+    let device_policy = device.ip_policy.unwrap_or_default();
+    // Resolve once per round so every check sees the same addresses
+    let (targets, dns_failures) =
+        resolve_targets(&device.ips, device.timeout.unwrap_or(timeout_sec)).await;
+    let targets = Arc::new(targets);
+    let mut tasks = tokio::task::JoinSet::new();
+
+    for &index in due {
+        let check = &device.checks[index];
+        let check_clone = check.clone();
+        let targets_clone = targets.clone();
+        let dns_failures_clone = dns_failures.clone();
+        let sem_clone = semaphore.clone();
+        let to_sec = device.check_timeout(check, timeout_sec);
+
+        tasks.spawn(async move {
+            let mut probes =
+                check_item_with_parallel_ip(&check_clone, &targets_clone, to_sec, sem_clone).await;
+            probes.extend(
+                dns_failures_clone
+                    .into_iter()
+                    .map(|(host, reason)| ProbeResult {
+                        ip: host,
+                        outcome: Err(reason),
+                        kind: FailureKind::Dns,
+                    }),
+            );
+            CheckResult {
+                index,
+                policy: check_clone.ip_policy.unwrap_or(device_policy),
+                check: check_clone,
+                probes,
+            }
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        if let Ok(check_result) = result {
+            results.push(check_result);
+        }
+    }
+    results
+}
+
+/// A failed device together with its failed checks
+type DeviceFailures = (Device, Vec<CheckFailure>);
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CheckFailure {
+    check_name: String,
+    port: u16,
+    attempted_ips: Vec<String>,
+    /// Why the probes failed (connect error, HTTP status, body mismatch...)
+    #[serde(default)]
+    reason: String,
+    #[serde(default)]
+    severity: Severity,
+    /// dns when every affected entry is a hostname that did not resolve
+    #[serde(default)]
+    kind: FailureKind,
+    /// Targets (resolved addresses) that passed, out of all probed this round
+    #[serde(default)]
+    targets_up: usize,
+    #[serde(default)]
+    targets_total: usize,
+}
+
+impl CheckFailure {
+    /// "Port：22" in rendered alerts, "ICMP" for ping checks (which have no port)
+    fn port_label(&self) -> String {
+        if self.port == 0 {
+            "ICMP".to_string()
+        } else {
+            format!("Port：{}", self.port)
+        }
+    }
+}
+
+/// Error: the check is down. Warning: the check passes but needs attention soon
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Warning,
+    #[default]
+    Error,
+}
+
+impl Severity {
+    /// Title suffix of single-device alerts
+    fn label(&self) -> &'static str {
+        match self {
+            Severity::Warning => "Warning",
+            Severity::Error => "Failure Alert",
+        }
+    }
+
+    /// Title suffix of group digests
+    fn digest_label(&self) -> &'static str {
+        match self {
+            Severity::Warning => "Group Warning Digest",
+            Severity::Error => "Group Failure Digest",
+        }
+    }
+
+    /// Marker of a check header / affected IP in rendered trees
+    fn markers(&self) -> (&'static str, &'static str) {
+        match self {
+            Severity::Warning => ("🟡", "⚠️"),
+            Severity::Error => ("🔴", "❌"),
+        }
+    }
+}
+
+// ────────────────────────────────────────────────────────────
+// Alert Events (Shared Data Model for All Notifiers)
+// ────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum AlertKind {
+    Failure,
+    Recovery,
+}
+
+/// One device inside an alert event
+#[derive(Clone, Serialize)]
+struct DeviceAlert {
+    device: Device,
+    failures: Vec<CheckFailure>,
+    first_failure_ts: i64,
+    recovered_ts: Option<i64>,
+    duration_secs: u64,
+}
+
+impl DeviceAlert {
+    fn failed(
+        device: Device,
+        failures: Vec<CheckFailure>,
+        first_failure_ts: i64,
+        now_ts: i64,
+    ) -> Self {
+        Self {
+            device,
+            failures,
+            first_failure_ts,
+            recovered_ts: None,
+            duration_secs: (now_ts - first_failure_ts).max(0) as u64,
+        }
+    }
+
+    /// Most severe level among the failed checks
+    fn severity(&self) -> Severity {
+        self.failures
+            .iter()
+            .map(|f| f.severity)
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn recovered(device: Device, recovery: Recovery) -> Self {
+        Self {
+            duration_secs: recovery.duration_secs(),
+            device,
+            failures: recovery.failures,
+            first_failure_ts: recovery.first_failure_ts,
+            recovered_ts: Some(recovery.recovered_ts),
+        }
+    }
+}
+
+/// Unit of delivery: a single device, or a group digest when it holds several devices
+#[derive(Clone, Serialize)]
+struct AlertEvent {
+    kind: AlertKind,
+    group: String,
+    timestamp: i64,
+    alerts: Vec<DeviceAlert>,
+}
+
+impl AlertEvent {
+    fn is_digest(&self) -> bool {
+        self.alerts.len() > 1
+    }
+
+    /// One-line summary used as message title / mail subject
+    fn title(&self) -> String {
+        match (self.kind, self.alerts.as_slice()) {
+            (AlertKind::Failure, [alert]) => format!(
+                "{} {} {}",
+                priority_emoji(&alert.device.priority),
+                alert.device.name,
+                alert.severity().label()
+            ),
+            (AlertKind::Recovery, [alert]) => format!("✅ {} Recovered", alert.device.name),
+            (AlertKind::Failure, _) => format!(
+                "{} {} {}",
+                priority_emoji(self.top_priority()),
+                self.group,
+                self.severity().digest_label()
+            ),
+            (AlertKind::Recovery, _) => format!("✅ {} Group Recovery Digest", self.group),
+        }
+    }
+
+    /// Most severe level among the devices of this event
+    fn severity(&self) -> Severity {
+        self.alerts
+            .iter()
+            .map(|a| a.severity())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Most severe priority among the devices of this event
+    fn top_priority(&self) -> &'static str {
+        ["critical", "high", "medium"]
+            .into_iter()
+            .find(|p| self.alerts.iter().any(|a| a.device.priority == *p))
+            .unwrap_or("low")
+    }
+}
+
+/// Split the alerts of one group into events: a single digest, or one event per device
+fn group_events(
+    kind: AlertKind,
+    group: &str,
+    alerts: Vec<DeviceAlert>,
+    digest: bool,
+    now_ts: i64,
+) -> Vec<AlertEvent> {
+    let event = |alerts| AlertEvent {
+        kind,
+        group: group.to_string(),
+        timestamp: now_ts,
+        alerts,
+    };
+
+    if alerts.is_empty() {
+        Vec::new()
+    } else if digest {
+        vec![event(alerts)]
+    } else {
+        alerts.into_iter().map(|a| event(vec![a])).collect()
+    }
+}
+
+/// Render failed checks as a vertical tree (shared by failure and recovery messages)
+fn render_failure_tree(failures: &[CheckFailure]) -> String {
+    let mut detail = String::new();
+
+    for (idx, failure) in failures.iter().enumerate() {
+        let (header, mark) = failure.severity.markers();
+        detail.push_str(&format!(
+            "┌─ {} {} ({})\n",
+            header,
+            failure.check_name,
+            failure.port_label()
+        ));
+        if !failure.reason.is_empty() {
+            detail.push_str(&format!("│  ├─ 💬 {}\n", failure.reason));
+        }
+
+        let display_ips: Vec<&String> = failure.attempted_ips.iter().take(10).collect();
+        for (ip_idx, ip) in display_ips.iter().enumerate() {
+            let connector = if ip_idx == display_ips.len() - 1 {
+                "│  └─"
+            } else {
+                "│  ├─"
+            };
+            detail.push_str(&format!("{} {} {}\n", connector, mark, ip));
+        }
+
+        if failure.attempted_ips.len() > 10 {
+            detail.push_str(&format!(
+                "│  └─ ... {} more IPs\n",
+                failure.attempted_ips.len() - 10
+            ));
+        }
+
+        if idx < failures.len() - 1 {
+            detail.push_str("│\n");
+        }
+    }
+
+    detail.push_str(&format!(
+        "└─ 📊 Stats：{} checks failed | {} IPs affected\n",
+        failures.len(),
+        failures
+            .iter()
+            .map(|f| f.attempted_ips.len())
+            .sum::<usize>()
+    ));
+    detail
+}
+
+/// Render a group digest: one compact branch per device, failed IPs inline
+fn render_group_tree(alerts: &[DeviceAlert]) -> String {
+    let mut detail = String::new();
+
+    for (idx, alert) in alerts.iter().enumerate() {
+        let device = &alert.device;
+        detail.push_str(&format!(
+            "┌─ {} {} ({}) @ {}\n",
+            priority_emoji(&device.priority),
+            device.name,
+            device.id,
+            device.location
+        ));
+
+        for (f_idx, failure) in alert.failures.iter().enumerate() {
+            let connector = if f_idx == alert.failures.len() - 1 {
+                "│  └─"
+            } else {
+                "│  ├─"
+            };
+            let mut ips = failure
+                .attempted_ips
+                .iter()
+                .take(5)
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ");
+            if failure.attempted_ips.len() > 5 {
+                ips.push_str(&format!(" +{}", failure.attempted_ips.len() - 5));
+            }
+            if !failure.reason.is_empty() {
+                ips.push_str(&format!(" | 💬 {}", failure.reason));
+            }
+            detail.push_str(&format!(
+                "{} {} ({}) {} {}\n",
+                connector,
+                failure.check_name,
+                failure.port_label(),
+                failure.severity.markers().1,
+                ips
+            ));
+        }
+
+        if idx < alerts.len() - 1 {
+            detail.push_str("│\n");
+        }
+    }
+
+    detail.push_str(&format!(
+        "└─ 📊 Stats：{} devices | {} checks failed | {} IPs affected\n",
+        alerts.len(),
+        alerts.iter().map(|a| a.failures.len()).sum::<usize>(),
+        alerts
+            .iter()
+            .flat_map(|a| a.failures.iter())
+            .map(|f| f.attempted_ips.len())
+            .sum::<usize>()
+    ));
+    detail
+}
+
+/// Render a recovery digest: one line per device with outage window
+fn render_recovery_list(alerts: &[DeviceAlert]) -> String {
+    let mut detail = String::new();
+    for (idx, alert) in alerts.iter().enumerate() {
+        let connector = if idx == alerts.len() - 1 {
+            "└─"
+        } else {
+            "├─"
+        };
+        detail.push_str(&format!(
+            "{} ✅ {} ({}) | Outage {} | {} → {}\n",
+            connector,
+            alert.device.name,
+            alert.device.id,
+            format_duration(alert.duration_secs),
+            format_ts(alert.first_failure_ts),
+            format_ts(alert.recovered_ts.unwrap_or(alert.first_failure_ts))
+        ));
+    }
+    detail
+}
+
+/// Plain text rendering of an event (mail body, `{{ text }}` template variable)
+fn render_plain_text(event: &AlertEvent) -> String {
+    let mut body = format!("{}\n\n", event.title());
+    for alert in &event.alerts {
+        let device = &alert.device;
+        body.push_str(&format!(
+            "Device：{} ({})\n\
+            Location：{}\n\
+            OS：{} | Group：{}\n\
+            Priority：{}\n",
+            device.name, device.id, device.location, device.os, device.group, device.priority
+        ));
+        match alert.recovered_ts {
+            Some(recovered_ts) => body.push_str(&format!(
+                "Outage：{} ({} → {})\n\n",
+                format_duration(alert.duration_secs),
+                format_ts(alert.first_failure_ts),
+                format_ts(recovered_ts)
+            )),
+            None => body.push_str(&format!(
+                "Failing since：{}\n\n",
+                format_ts(alert.first_failure_ts)
+            )),
+        }
+        body.push_str(&render_failure_tree(&alert.failures));
+        body.push('\n');
+    }
+    body
+}
+
+fn priority_emoji(priority: &str) -> &'static str {
+    match priority {
+        "critical" => "🔴",
+        "high" => "🟠",
+        "medium" => "🟡",
+        _ => "🔵",
+    }
+}
+
+/// Human readable outage duration, e.g. `1h 05m 12s`
+fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    if h > 0 {
+        format!("{}h {:02}m {:02}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+fn format_ts(ts: i64) -> String {
+    chrono::DateTime::from_timestamp(ts, 0)
+        .map(|dt| {
+            dt.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| ts.to_string())
+}
+
+// ────────────────────────────────────────────────────────────
+// Notifiers (Pluggable Trait + Fan-out + Per-destination Retry Policy)
+// ────────────────────────────────────────────────────────────
+
+type NotifyError = Box<dyn std::error::Error + Send + Sync>;
+
+#[async_trait]
+trait Notifier: Send + Sync {
+    /// Deliver one event; an error triggers the retry policy of this destination
+    async fn send(&self, event: &AlertEvent) -> Result<(), NotifyError>;
+}
+
+/// A configured destination: the notifier plus its name and retry policy
+struct NotifierHandle {
+    name: String,
+    retry: RetryPolicy,
+    notifier: Arc<dyn Notifier>,
+}
+
+impl NotifierHandle {
+    async fn deliver(&self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let mut attempt = 1;
+        loop {
+            match self.notifier.send(event).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= self.retry.attempts => return Err(e),
+                Err(e) => warn!(
+                    "[{}] Alert delivery failed (attempt {}/{}): {}",
+                    self.name, attempt, self.retry.attempts, e
+                ),
+            }
+
+            sleep(Duration::from_millis(
+                self.retry.backoff_ms * attempt as u64,
+            ))
+            .await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Fan an event out to every destination concurrently, return the number of failed deliveries
+async fn dispatch(
+    notifiers: &[Arc<NotifierHandle>],
+    event: AlertEvent,
+    metrics: &Metrics,
+) -> usize {
+    let event = Arc::new(event);
+    let mut tasks = tokio::task::JoinSet::new();
+
+    for handle in notifiers {
+        let handle = handle.clone();
+        let event = event.clone();
+        tasks.spawn(async move {
+            let result = handle.deliver(&event).await;
+            if let Err(e) = &result {
+                error!(
+                    "[{}] Alert delivery gave up after {} attempts: {}",
+                    handle.name, handle.retry.attempts, e
+                );
+            }
+            (handle.name.clone(), result.is_ok())
+        });
+    }
+
+    let mut failed = 0;
+    while let Some(result) = tasks.join_next().await {
+        if let Ok((name, ok)) = &result {
+            metrics.record_notification(name, *ok);
+        }
+        if !matches!(result, Ok((_, true))) {
+            failed += 1;
+        }
+    }
+    failed
+}
+
+fn build_notifiers(
+    config: &Config,
+) -> Result<Vec<Arc<NotifierHandle>>, Box<dyn std::error::Error>> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+
+    config
+        .notifier_configs()
+        .into_iter()
+        .map(|cfg| {
+            let notifier: Arc<dyn Notifier> = match cfg.kind {
+                NotifierKind::Wecom { url } => Arc::new(WeComNotifier {
+                    client: client.clone(),
+                    url,
+                }),
+                NotifierKind::Webhook {
+                    url,
+                    method,
+                    headers,
+                    body,
+                } => Arc::new(WebhookNotifier {
+                    client: client.clone(),
+                    url,
+                    method: reqwest::Method::from_bytes(method.to_uppercase().as_bytes())?,
+                    headers,
+                    body,
+                }),
+                NotifierKind::Dingtalk {
+                    url,
+                    secret,
+                    keyword,
+                    at_mobiles,
+                    at_all,
+                } => Arc::new(DingTalkNotifier {
+                    client: client.clone(),
+                    url,
+                    secret,
+                    keyword,
+                    at_mobiles,
+                    at_all,
+                }),
+                NotifierKind::Feishu { url, secret } => Arc::new(FeishuNotifier {
+                    client: client.clone(),
+                    url,
+                    secret,
+                }),
+                NotifierKind::Email {
+                    host,
+                    port,
+                    tls,
+                    username,
+                    password,
+                    from,
+                    to,
+                    group_to,
+                    priority_to,
+                } => Arc::new(EmailNotifier {
+                    transport: build_smtp_transport(&host, port, tls, username, password)?,
+                    from: from.parse()?,
+                    to,
+                    group_to,
+                    priority_to,
+                }),
+            };
+            Ok(Arc::new(NotifierHandle {
+                name: cfg.name,
+                retry: cfg.retry,
+                notifier,
+            }))
+        })
+        .collect()
+}
+
+// ────────────────────────────────────────────────────────────
+// WeCom Notifier (Markdown - Clear Vertical Layout + Recovery Notice + Group Digest)
+// ────────────────────────────────────────────────────────────
+
+struct WeComNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WeComNotifier {
+    fn render(event: &AlertEvent) -> String {
+        match (event.kind, event.alerts.as_slice()) {
+            (AlertKind::Failure, [alert]) => Self::render_alert(alert),
+            (AlertKind::Recovery, [alert]) => Self::render_recovery(alert),
+            (AlertKind::Failure, _) => Self::render_group_alert(event),
+            (AlertKind::Recovery, _) => Self::render_group_recovery(event),
+        }
+    }
+
+    fn render_alert(alert: &DeviceAlert) -> String {
+        let device = &alert.device;
+        format!(
+            "{} **{}** {}\n\n\
+            > 📍 Location：{}\n\
+            > 💻 OS：{} | 🏷️ Group：{}\n\
+            > ⚠️ Priority：{}\n\n\
+            **Failure Details**：\n```\n{}```\n\n\
+            ---\n\
+            <font color=\"warning\">Recommendation：Check device power/network/service status</font>",
+            priority_emoji(&device.priority),
+            device.name,
+            alert.severity().label(),
+            device.location,
+            device.os,
+            device.group,
+            device.priority,
+            render_failure_tree(&alert.failures)
+        )
+    }
+
+    fn render_recovery(alert: &DeviceAlert) -> String {
+        let device = &alert.device;
+        format!(
+            "✅ **{}** Recovered\n\n\
+            > 📍 Location：{}\n\
+            > 💻 OS：{} | 🏷️ Group：{}\n\
+            > ⏱️ Outage：<font color=\"info\">{}</font>\n\
+            > 🕒 First failure：{}\n\
+            > 🕒 Recovered：{}\n\n\
+            **Failed Checks**：\n```\n{}```",
+            device.name,
+            device.location,
+            device.os,
+            device.group,
+            format_duration(alert.duration_secs),
+            format_ts(alert.first_failure_ts),
+            format_ts(alert.recovered_ts.unwrap_or(alert.first_failure_ts)),
+            render_failure_tree(&alert.failures)
+        )
+    }
+
+    fn render_group_alert(event: &AlertEvent) -> String {
+        format!(
+            "{} **{}** {}\n\n\
+            > 🏷️ Group：{}\n\
+            > ⚠️ Devices affected：<font color=\"warning\">{}</font>\n\n\
+            **Affected Devices**：\n```\n{}```\n\n\
+            ---\n\
+            <font color=\"warning\">Recommendation：Check shared switch/power/uplink of this group first</font>",
+            priority_emoji(event.top_priority()),
+            event.group,
+            event.severity().digest_label(),
+            event.group,
+            event.alerts.len(),
+            render_group_tree(&event.alerts)
+        )
+    }
+
+    fn render_group_recovery(event: &AlertEvent) -> String {
+        format!(
+            "✅ **{}** Group Recovery Digest\n\n\
+            > 🏷️ Group：{}\n\
+            > 🔄 Devices recovered：<font color=\"info\">{}</font>\n\n\
+            **Recovered Devices**：\n```\n{}```",
+            event.group,
+            event.group,
+            event.alerts.len(),
+            render_recovery_list(&event.alerts)
+        )
+    }
+}
+
+#[async_trait]
+impl Notifier for WeComNotifier {
+    async fn send(&self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let payload = serde_json::json!({
+            "msgtype": "markdown",
+            "markdown": { "content": Self::render(event) }
+        });
+
+        let resp = self.client.post(&self.url).json(&payload).send().await?;
+        check_robot_response(resp).await
+    }
+}
+
+/// Robot APIs (WeCom, DingTalk, Feishu) answer HTTP 200 even for rejected messages,
+/// the real result is in `errcode`/`errmsg` (Feishu: `code`/`msg`)
+async fn check_robot_response(resp: reqwest::Response) -> Result<(), NotifyError> {
+    if !resp.status().is_success() {
+        return Err(format!("HTTP {}", resp.status()).into());
+    }
+
+    let body: serde_json::Value = resp.json().await.unwrap_or_default();
+    let code = body.get("errcode").or_else(|| body.get("code"));
+    match code.and_then(|c| c.as_i64()) {
+        Some(0) | None => Ok(()),
+        Some(code) => Err(format!(
+            "errcode {}: {}",
+            code,
+            body.get("errmsg")
+                .or_else(|| body.get("msg"))
+                .and_then(|m| m.as_str())
+                .unwrap_or("")
+        )
+        .into()),
+    }
+}
+
+// ────────────────────────────────────────────────────────────
+// DingTalk Notifier (Markdown + HMAC-SHA256 Signed Requests + Keyword Guard)
+// ────────────────────────────────────────────────────────────
+
+struct DingTalkNotifier {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+    keyword: String,
+    at_mobiles: Vec<String>,
+    at_all: bool,
+}
+
+impl DingTalkNotifier {
+    /// DingTalk markdown drops single newlines, so tree lines become separate paragraphs
+    fn paragraphs(text: &str) -> String {
+        text.lines().collect::<Vec<_>>().join("\n\n")
+    }
+
+    /// Returns (title, text) of the markdown message
+    fn render(&self, event: &AlertEvent) -> (String, String) {
+        let (title, body) = match (event.kind, event.alerts.as_slice()) {
+            (AlertKind::Failure, [alert]) => {
+                let device = &alert.device;
+                (
+                    event.title(),
+                    format!(
+                        "> 📍 Location：{}\n\n\
+                        > 💻 OS：{} | 🏷️ Group：{}\n\n\
+                        > ⚠️ Priority：{}\n\n\
+                        **Failure Details**：\n\n{}\n\n\
+                        ---\n\n\
+                        Recommendation：Check device power/network/service status",
+                        device.location,
+                        device.os,
+                        device.group,
+                        device.priority,
+                        Self::paragraphs(&render_failure_tree(&alert.failures))
+                    ),
+                )
+            }
+            (AlertKind::Recovery, [alert]) => {
+                let device = &alert.device;
+                (
+                    format!("✅ {} Recovered", device.name),
+                    format!(
+                        "> 📍 Location：{}\n\n\
+                        > 💻 OS：{} | 🏷️ Group：{}\n\n\
+                        > ⏱️ Outage：{}\n\n\
+                        > 🕒 First failure：{}\n\n\
+                        > 🕒 Recovered：{}\n\n\
+                        **Failed Checks**：\n\n{}",
+                        device.location,
+                        device.os,
+                        device.group,
+                        format_duration(alert.duration_secs),
+                        format_ts(alert.first_failure_ts),
+                        format_ts(alert.recovered_ts.unwrap_or(alert.first_failure_ts)),
+                        Self::paragraphs(&render_failure_tree(&alert.failures))
+                    ),
+                )
+            }
+            (AlertKind::Failure, _) => (
+                event.title(),
+                format!(
+                    "> 🏷️ Group：{}\n\n\
+                    > ⚠️ Devices affected：{}\n\n\
+                    **Affected Devices**：\n\n{}\n\n\
+                    ---\n\n\
+                    Recommendation：Check shared switch/power/uplink of this group first",
+                    event.group,
+                    event.alerts.len(),
+                    Self::paragraphs(&render_group_tree(&event.alerts))
+                ),
+            ),
+            (AlertKind::Recovery, _) => (
+                format!("✅ {} Group Recovery Digest", event.group),
+                format!(
+                    "> 🏷️ Group：{}\n\n\
+                    > 🔄 Devices recovered：{}\n\n\
+                    **Recovered Devices**：\n\n{}",
+                    event.group,
+                    event.alerts.len(),
+                    Self::paragraphs(&render_recovery_list(&event.alerts))
+                ),
+            ),
+        };
+
+        // Keyword-protected robots reject messages whose content lacks the keyword
+        let title = if self.keyword.is_empty() || title.contains(&self.keyword) {
+            title
+        } else {
+            format!("[{}] {}", self.keyword, title)
+        };
+        let mentions: String = self.at_mobiles.iter().map(|m| format!(" @{}", m)).collect();
+        let text = format!("### {}\n\n{}{}", title, body, mentions);
+        (title, text)
+    }
+
+    /// Append `timestamp` and `sign` (HMAC-SHA256 of "timestamp\nsecret", base64) to the URL
+    fn signed_url(&self) -> Result<reqwest::Url, NotifyError> {
+        let mut url = reqwest::Url::parse(&self.url)?;
+        if self.secret.is_empty() {
+            return Ok(url);
+        }
+
+        let timestamp = Local::now().timestamp_millis().to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())?;
+        mac.update(format!("{}\n{}", timestamp, self.secret).as_bytes());
+        let sign = BASE64.encode(mac.finalize().into_bytes());
+
+        url.query_pairs_mut()
+            .append_pair("timestamp", &timestamp)
+            .append_pair("sign", &sign);
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl Notifier for DingTalkNotifier {
+    async fn send(&self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let (title, text) = self.render(event);
+        let payload = serde_json::json!({
+            "msgtype": "markdown",
+            "markdown": { "title": title, "text": text },
+            "at": { "atMobiles": self.at_mobiles, "isAtAll": self.at_all }
+        });
+
+        // Sign per attempt: DingTalk rejects timestamps older than one hour
+        let resp = self
+            .client
+            .post(self.signed_url()?)
+            .json(&payload)
+            .send()
+            .await?;
+        check_robot_response(resp).await
+    }
+}
+
+// ────────────────────────────────────────────────────────────
+// Feishu/Lark Notifier (Interactive Card + Priority Colored Header + Signature)
+// ────────────────────────────────────────────────────────────
+
+struct FeishuNotifier {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+}
+
+impl FeishuNotifier {
+    fn header_template(event: &AlertEvent) -> &'static str {
+        if event.kind == AlertKind::Recovery {
+            return "green";
+        }
+        if event.severity() == Severity::Warning {
+            return "yellow";
+        }
+        match event.top_priority() {
+            "critical" => "red",
+            "high" => "orange",
+            "medium" => "yellow",
+            _ => "blue",
+        }
+    }
+
+    fn lark_md(content: String) -> serde_json::Value {
+        serde_json::json!({ "tag": "lark_md", "content": content })
+    }
+
+    fn field(label: &str, value: &str) -> serde_json::Value {
+        serde_json::json!({
+            "is_short": true,
+            "text": Self::lark_md(format!("**{}**\n{}", label, value))
+        })
+    }
+
+    /// Location / OS / group (and outage window for recoveries) as short fields
+    fn device_fields(alert: &DeviceAlert) -> serde_json::Value {
+        let device = &alert.device;
+        let mut fields = vec![
+            Self::field("📍 Location", &device.location),
+            Self::field("💻 OS", &device.os),
+            Self::field("🏷️ Group", &device.group),
+            Self::field("⚠️ Priority", &device.priority),
+        ];
+        if let Some(recovered_ts) = alert.recovered_ts {
+            fields.push(Self::field(
+                "⏱️ Outage",
+                &format_duration(alert.duration_secs),
+            ));
+            fields.push(Self::field(
+                "🕒 Window",
+                &format!(
+                    "{} → {}",
+                    format_ts(alert.first_failure_ts),
+                    format_ts(recovered_ts)
+                ),
+            ));
+        }
+        serde_json::json!({ "tag": "div", "fields": fields })
+    }
+
+    /// One block per failed check with its failed IP list
+    fn check_blocks(failures: &[CheckFailure]) -> Vec<serde_json::Value> {
+        failures
+            .iter()
+            .map(|failure| {
+                let mut ips: Vec<String> = failure
+                    .attempted_ips
+                    .iter()
+                    .take(10)
+                    .map(|ip| format!("{} {}", failure.severity.markers().1, ip))
+                    .collect();
+                if failure.attempted_ips.len() > 10 {
+                    ips.push(format!("... {} more IPs", failure.attempted_ips.len() - 10));
+                }
+                if !failure.reason.is_empty() {
+                    ips.insert(0, format!("💬 {}", failure.reason));
+                }
+                serde_json::json!({
+                    "tag": "div",
+                    "text": Self::lark_md(format!(
+                        "**{} {} ({})**\n{}",
+                        failure.severity.markers().0,
+                        failure.check_name,
+                        failure.port_label(),
+                        ips.join("\n")
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    fn card(event: &AlertEvent) -> serde_json::Value {
+        let title = if event.is_digest() {
+            format!("{} ({} devices)", event.title(), event.alerts.len())
+        } else {
+            event.title()
+        };
+
+        let mut elements = Vec::new();
+        for (idx, alert) in event.alerts.iter().enumerate() {
+            if idx > 0 {
+                elements.push(serde_json::json!({ "tag": "hr" }));
+            }
+            if event.is_digest() {
+                elements.push(serde_json::json!({
+                    "tag": "div",
+                    "text": Self::lark_md(format!(
+                        "{} **{}** ({})",
+                        priority_emoji(&alert.device.priority),
+                        alert.device.name,
+                        alert.device.id
+                    ))
+                }));
+            }
+            elements.push(Self::device_fields(alert));
+            elements.extend(Self::check_blocks(&alert.failures));
+        }
+
+        let note = match event.kind {
+            AlertKind::Failure => "Recommendation：Check device power/network/service status",
+            AlertKind::Recovery => "All checks passing again",
+        };
+        elements.push(serde_json::json!({
+            "tag": "note",
+            "elements": [{ "tag": "plain_text", "content": format!("{} | {}", note, format_ts(event.timestamp)) }]
+        }));
+
+        serde_json::json!({
+            "config": { "wide_screen_mode": true },
+            "header": {
+                "title": { "tag": "plain_text", "content": title },
+                "template": Self::header_template(event)
+            },
+            "elements": elements
+        })
+    }
+
+    /// Feishu signature: HMAC-SHA256 keyed with "timestamp\nsecret" over an empty message, base64
+    fn sign(&self, timestamp: i64) -> Result<String, NotifyError> {
+        let key = format!("{}\n{}", timestamp, self.secret);
+        let mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())?;
+        Ok(BASE64.encode(mac.finalize().into_bytes()))
+    }
+}
+
+#[async_trait]
+impl Notifier for FeishuNotifier {
+    async fn send(&self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let mut payload = serde_json::json!({
+            "msg_type": "interactive",
+            "card": Self::card(event)
+        });
+
+        if !self.secret.is_empty() {
+            let timestamp = Local::now().timestamp();
+            payload["timestamp"] = serde_json::json!(timestamp.to_string());
+            payload["sign"] = serde_json::json!(self.sign(timestamp)?);
+        }
+
+        let resp = self.client.post(&self.url).json(&payload).send().await?;
+        check_robot_response(resp).await
+    }
+}
+
+// ────────────────────────────────────────────────────────────
+// Email Notifier (SMTP + STARTTLS/TLS + Recipient Routing by Group/Priority)
+// ────────────────────────────────────────────────────────────
+
+struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<String>,
+    group_to: HashMap<String, Vec<String>>,
+    priority_to: HashMap<String, Vec<String>>,
+}
+
+fn build_smtp_transport(
+    host: &str,
+    port: Option<u16>,
+    tls: SmtpTls,
+    username: String,
+    password: String,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, Box<dyn std::error::Error>> {
+    let (builder, default_port) = match tls {
+        SmtpTls::None => (
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+            25,
+        ),
+        SmtpTls::Starttls => (
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            587,
+        ),
+        SmtpTls::Tls => (AsyncSmtpTransport::<Tokio1Executor>::relay(host)?, 465),
+    };
+
+    let mut builder = builder
+        .port(port.unwrap_or(default_port))
+        .timeout(Some(Duration::from_secs(10)));
+    if !username.is_empty() {
+        builder = builder.credentials(Credentials::new(username, password));
+    }
+    Ok(builder.build())
+}
+
+impl EmailNotifier {
+    /// `to` plus the group and priority specific lists of every device in the event
+    fn recipients(&self, event: &AlertEvent) -> Vec<&String> {
+        let mut recipients: Vec<&String> = self.to.iter().collect();
+        recipients.extend(self.group_to.get(&event.group).into_iter().flatten());
+        for alert in &event.alerts {
+            recipients.extend(
+                self.priority_to
+                    .get(&alert.device.priority)
+                    .into_iter()
+                    .flatten(),
+            );
+        }
+
+        let mut seen = HashSet::new();
+        recipients.retain(|r| seen.insert(r.to_lowercase()));
+        recipients
+    }
+
+    fn render_html(event: &AlertEvent) -> String {
+        let color = match (event.kind, event.top_priority()) {
+            (AlertKind::Recovery, _) => "#2e7d32",
+            _ if event.severity() == Severity::Warning => "#f9a825",
+            (_, "critical") => "#c62828",
+            (_, "high") => "#ef6c00",
+            (_, "medium") => "#f9a825",
+            _ => "#1565c0",
+        };
+
+        let mut html = format!(
+            "<html><body style=\"font-family:sans-serif\">\
+            <h2 style=\"color:{}\">{}</h2>",
+            color,
+            html_escape(&event.title())
+        );
+        for alert in &event.alerts {
+            let device = &alert.device;
+            let mut rows = vec![
+                ("Device", format!("{} ({})", device.name, device.id)),
+                ("Location", device.location.clone()),
+                ("OS", device.os.clone()),
+                ("Group", device.group.clone()),
+                ("Priority", device.priority.clone()),
+                ("First failure", format_ts(alert.first_failure_ts)),
+            ];
+            if let Some(recovered_ts) = alert.recovered_ts {
+                rows.push(("Recovered", format_ts(recovered_ts)));
+                rows.push(("Outage", format_duration(alert.duration_secs)));
+            }
+
+            html.push_str("<table cellpadding=\"4\" style=\"border-collapse:collapse\">");
+            for (label, value) in rows {
+                html.push_str(&format!(
+                    "<tr><td><b>{}</b></td><td>{}</td></tr>",
+                    label,
+                    html_escape(&value)
+                ));
+            }
+            html.push_str("</table>");
+
+            for failure in &alert.failures {
+                html.push_str(&format!(
+                    "<p><b>{} {} ({})</b></p><ul>",
+                    failure.severity.markers().0,
+                    html_escape(&failure.check_name),
+                    failure.port_label()
+                ));
+                if !failure.reason.is_empty() {
+                    html.push_str(&format!("<li>💬 {}</li>", html_escape(&failure.reason)));
+                }
+                for ip in &failure.attempted_ips {
+                    html.push_str(&format!(
+                        "<li>{} {}</li>",
+                        failure.severity.markers().1,
+                        html_escape(ip)
+                    ));
+                }
+                html.push_str("</ul>");
+            }
+            html.push_str("<hr>");
+        }
+        html.push_str(&format!(
+            "<p style=\"color:#888\">port-sentinel-rs | {}</p></body></html>",
+            format_ts(event.timestamp)
+        ));
+        html
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    async fn send(&self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let recipients = self.recipients(event);
+        if recipients.is_empty() {
+            debug!("No email recipients for group {}, skipped", event.group);
+            return Ok(());
+        }
+
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(event.title());
+        for recipient in recipients {
+            builder = builder.to(recipient.parse()?);
+        }
+        let message = builder.multipart(MultiPart::alternative_plain_html(
+            render_plain_text(event),
+            Self::render_html(event),
+        ))?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+// ────────────────────────────────────────────────────────────
+// Generic Webhook Notifier (Alert Event JSON or User-defined Payload Template)
+// ────────────────────────────────────────────────────────────
+
+struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+    method: reqwest::Method,
+    headers: HashMap<String, String>,
+    body: Option<String>,
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn send(&self, event: &AlertEvent) -> Result<(), NotifyError> {
+        let ctx = template_context(event);
+        let url = render_template(&self.url, &ctx)?;
+        let mut request = self.client.request(self.method.clone(), url);
+
+        let mut has_content_type = false;
+        for (name, value) in &self.headers {
+            has_content_type |= name.eq_ignore_ascii_case("content-type");
+            request = request.header(name, render_template(value, &ctx)?);
+        }
+
+        request = match &self.body {
+            Some(template) => {
+                if !has_content_type {
+                    request = request.header("Content-Type", "application/json");
+                }
+                request.body(render_template(template, &ctx)?)
+            }
+            None => request.json(event),
+        };
+
+        let resp = request.send().await?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("HTTP {}", resp.status()).into())
+        }
+    }
+}
+
+/// Variables available to webhook templates; digest events expose every device in `alerts`,
+/// while `device`/`failures`/timestamps describe the first one
+fn template_context(event: &AlertEvent) -> serde_json::Value {
+    let (from_state, to_state) = match event.kind {
+        AlertKind::Failure => ("ok", "failed"),
+        AlertKind::Recovery => ("failed", "ok"),
+    };
+    let first = event.alerts.first();
+
+    serde_json::json!({
+        "kind": event.kind,
+        "severity": event.severity(),
+        "from_state": from_state,
+        "to_state": to_state,
+        "group": event.group,
+        "title": event.title(),
+        "text": render_plain_text(event),
+        "timestamp": event.timestamp,
+        "time": format_ts(event.timestamp),
+        "device_count": event.alerts.len(),
+        "alerts": event.alerts,
+        "device": first.map(|a| &a.device),
+        "failures": first.map(|a| &a.failures),
+        "failures_text": first.map(|a| render_failure_tree(&a.failures)),
+        "first_failure_ts": first.map(|a| a.first_failure_ts),
+        "first_failure_time": first.map(|a| format_ts(a.first_failure_ts)),
+        "recovered_ts": first.and_then(|a| a.recovered_ts),
+        "recovered_time": first.and_then(|a| a.recovered_ts).map(format_ts),
+        "duration_secs": first.map(|a| a.duration_secs),
+        "duration": first.map(|a| format_duration(a.duration_secs)),
+    })
+}
+
+/// Minimal template engine: `{{ path.to.value }}` inserts the value as text,
+/// `{{ path | json }}` inserts it JSON-encoded (quoted/escaped strings, arrays, objects)
+fn render_template(template: &str, ctx: &serde_json::Value) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| "unclosed '{{' in template".to_string())?;
+
+        let expr = after[..end].trim();
+        let (path, filter) = match expr.split_once('|') {
+            Some((path, filter)) => (path.trim(), Some(filter.trim())),
+            None => (expr, None),
+        };
+
+        let value = path
+            .split('.')
+            .try_fold(ctx, |value, key| match value {
+                serde_json::Value::Object(map) => map.get(key),
+                serde_json::Value::Array(items) => {
+                    key.parse::<usize>().ok().and_then(|i| items.get(i))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| format!("unknown template variable '{}'", path))?;
+
+        match (filter, value) {
+            (Some("json"), value) => out.push_str(&value.to_string()),
+            (Some(other), _) => return Err(format!("unknown template filter '{}'", other)),
+            (None, serde_json::Value::String(text)) => out.push_str(text),
+            (None, serde_json::Value::Null) => {}
+            (None, value) => out.push_str(&value.to_string()),
+        }
+        rest = &after[end + 2..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+// ────────────────────────────────────────────────────────────
+// Metrics (Prometheus Text Exposition + Embedded HTTP Listener)
+// ────────────────────────────────────────────────────────────
+
+const ROUND_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+const CONNECT_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Per-bucket (non-cumulative) counts, cumulated at render time
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Everything exported on /metrics; label sets are pre-rendered `k="v",...` strings
+struct MetricsData {
+    rounds: u64,
+    round_duration: Histogram,
+    alerts: u64,
+    recoveries: u64,
+    device_up: BTreeMap<String, u8>,
+    check_up: BTreeMap<String, u8>,
+    connect_duration: BTreeMap<String, Histogram>,
+    connect_failures: BTreeMap<String, u64>,
+    dns_failures: BTreeMap<String, u64>,
+    server_versions: BTreeMap<String, String>,
+    packet_loss: BTreeMap<String, f64>,
+    notifications: BTreeMap<String, u64>,
 }
 
-impl AlertState {
+struct Metrics {
+    data: std::sync::Mutex<MetricsData>,
+}
+
+impl Metrics {
     fn new() -> Self {
         Self {
-            last_alert: HashMap::new(),
-            is_failed: HashMap::new(),
+            data: std::sync::Mutex::new(MetricsData {
+                rounds: 0,
+                round_duration: Histogram::new(ROUND_BUCKETS),
+                alerts: 0,
+                recoveries: 0,
+                device_up: BTreeMap::new(),
+                check_up: BTreeMap::new(),
+                connect_duration: BTreeMap::new(),
+                connect_failures: BTreeMap::new(),
+                dns_failures: BTreeMap::new(),
+                server_versions: BTreeMap::new(),
+                packet_loss: BTreeMap::new(),
+                notifications: BTreeMap::new(),
+            }),
         }
     }
 
-    /// Determine if alert should be sent (supports failure recovery detection + cooldown control)
-    fn should_alert(
-        &mut self,
-        device_id: &str,
-        currently_failed: bool,
-        now_ts: i64,
-        cooldown: u64,
-    ) -> bool {
-        let prev_failed = self.is_failed.get(device_id).copied().unwrap_or(false);
+    /// Raw per-round result of a device (thresholds and cooldown are not applied here).
+    /// `results` holds the latest result of every check; probe series are only fed from the
+    /// checks that actually ran this round (`fresh`), so cached results aren't counted twice
+    fn record_device(&self, device: &Device, results: &[&CheckResult], fresh: &HashSet<usize>) {
+        let mut data = self.data.lock().unwrap();
+        let device_labels = prom_labels(&[
+            ("device_id", &device.id),
+            ("name", &device.name),
+            ("group", &device.group),
+            ("priority", &device.priority),
+        ]);
+        let all_passed = results.iter().all(|r| r.passed());
+        data.device_up.insert(device_labels, all_passed as u8);
 
-        // If previously failed but now recovered, clear state without alert
-        if prev_failed && !currently_failed {
-            self.is_failed.remove(device_id);
-            return false;
+        // Every check carries the same unresolved hostnames, count them once per round
+        let unresolved: BTreeSet<&String> = results
+            .iter()
+            .filter(|r| fresh.contains(&r.index))
+            .flat_map(|r| &r.probes)
+            .filter(|p| p.kind == FailureKind::Dns)
+            .map(|p| &p.ip)
+            .collect();
+        for host in unresolved {
+            let labels = prom_labels(&[("device_id", &device.id), ("host", host)]);
+            *data.dns_failures.entry(labels).or_default() += 1;
         }
 
-        // Only alert if failed and cooldown period has passed
-        if !currently_failed {
-            return false;
-        }
+        for result in results {
+            let port = result.check.port.to_string();
+            let check_name = result.check.display_name();
+            let check_labels = prom_labels(&[
+                ("device_id", &device.id),
+                ("check", &check_name),
+                ("port", &port),
+            ]);
+            data.check_up.insert(check_labels, result.passed() as u8);
+            if !fresh.contains(&result.index) {
+                continue;
+            }
 
-        let last = self.last_alert.get(device_id).copied().unwrap_or(0);
-        if now_ts - last >= cooldown as i64 {
-            self.last_alert.insert(device_id.to_string(), now_ts);
-            self.is_failed
-                .insert(device_id.to_string(), currently_failed);
-            true
-        } else {
-            false
+            for probe in result
+                .probes
+                .iter()
+                .filter(|p| p.kind == FailureKind::Probe)
+            {
+                // Checks sharing a port (e.g. tcp and tls on 443) keep separate series
+                let probe_labels = prom_labels(&[
+                    ("device_id", &device.id),
+                    ("check", &check_name),
+                    ("ip", &probe.ip),
+                    ("port", &port),
+                ]);
+                if result.check.kind == CheckKind::Icmp {
+                    let loss = match &probe.outcome {
+                        Ok(ok) => ok.packet_loss.unwrap_or_default(),
+                        Err(_) => 1.0,
+                    };
+                    data.packet_loss.insert(probe_labels.clone(), loss);
+                }
+                match &probe.outcome {
+                    Ok(ok) => {
+                        if let Some(version) = &ok.version {
+                            data.server_versions
+                                .insert(probe_labels.clone(), version.clone());
+                        }
+                        data.connect_duration
+                            .entry(probe_labels)
+                            .or_insert_with(|| Histogram::new(CONNECT_BUCKETS))
+                            .observe(ok.latency.as_secs_f64())
+                    }
+                    Err(_) => *data.connect_failures.entry(probe_labels).or_default() += 1,
+                }
+            }
         }
     }
 
-    /// Mark device as recovered, return whether recovery actually occurred (for statistics)
-    fn mark_recovered(&mut self, device_id: &str) -> bool {
-        self.is_failed.remove(device_id).is_some()
+    /// After a reload: drop the gauges (the next round rebuilds them) and prune counters and
+    /// histograms to the devices and checks still in the config
+    fn retain_config(&self, devices: &[Device]) {
+        let prefix = |pairs: &[(&str, &str)]| format!("{},", prom_labels(pairs));
+        let device_prefixes: Vec<String> = devices
+            .iter()
+            .map(|d| prefix(&[("device_id", &d.id)]))
+            .collect();
+        let check_prefixes: Vec<String> = devices
+            .iter()
+            .flat_map(|d| {
+                d.checks
+                    .iter()
+                    .map(|c| prefix(&[("device_id", &d.id), ("check", &c.display_name())]))
+            })
+            .collect();
+        let keep = |prefixes: &[String], labels: &String| {
+            prefixes.iter().any(|p| labels.starts_with(p.as_str()))
+        };
+        let mut data = self.data.lock().unwrap();
+        data.device_up.clear();
+        data.check_up.clear();
+        data.server_versions.clear();
+        data.packet_loss.clear();
+        data.connect_duration
+            .retain(|labels, _| keep(&check_prefixes, labels));
+        data.connect_failures
+            .retain(|labels, _| keep(&check_prefixes, labels));
+        data.dns_failures
+            .retain(|labels, _| keep(&device_prefixes, labels));
     }
-}
 
-// ────────────────────────────────────────────────────────────
-// Core Detection Logic (Three-level Concurrency + Semaphore Rate Limiting + Resource Reuse)
-// ────────────────────────────────────────────────────────────
+    fn record_notification(&self, notifier: &str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        let labels = prom_labels(&[("notifier", notifier), ("result", result)]);
+        *self
+            .data
+            .lock()
+            .unwrap()
+            .notifications
+            .entry(labels)
+            .or_default() += 1;
+    }
 
-async fn check_port_with_semaphore(
-    ip: &str,
-    port: u16,
-    timeout_sec: u64,
-    semaphore: Arc<Semaphore>,
-) -> bool {
-    let _permit = semaphore.acquire().await.unwrap();
-    let addr = format!("{}:{}", ip, port);
-    let timeout_dur = Duration::from_secs(timeout_sec);
+    fn record_round(&self, duration: Duration, total_alerts: u64, recovered_count: u64) {
+        let mut data = self.data.lock().unwrap();
+        data.rounds += 1;
+        data.round_duration.observe(duration.as_secs_f64());
+        data.alerts = total_alerts;
+        data.recoveries = recovered_count;
+    }
 
-    matches!(
-        timeout(timeout_dur, TcpStream::connect(&addr)).await,
-        Ok(Ok(_))
-    )
-}
+    fn render(&self) -> String {
+        let data = self.data.lock().unwrap();
+        let mut out = String::new();
 
-async fn check_item_with_parallel_ip(
-    check: &CheckItem,
-    ips: &[String],
-    timeout_sec: u64,
-    semaphore: Arc<Semaphore>,
-) -> (bool, Vec<String>) {
-    let mut tasks = tokio::task::JoinSet::new();
+        prom_header(
+            &mut out,
+            "sentinel_rounds_total",
+            "counter",
+            "Completed detection rounds",
+        );
+        prom_sample(&mut out, "sentinel_rounds_total", "", data.rounds);
 
-    for ip in ips {
-        let ip_clone = ip.clone();
-        let sem_clone = semaphore.clone();
-        let port = check.port;
-        let to_sec = timeout_sec;
+        prom_header(
+            &mut out,
+            "sentinel_round_duration_seconds",
+            "histogram",
+            "Wall time of one detection round",
+        );
+        prom_histogram(
+            &mut out,
+            "sentinel_round_duration_seconds",
+            "",
+            &data.round_duration,
+        );
 
-        tasks.spawn(async move {
-            let success = check_port_with_semaphore(&ip_clone, port, to_sec, sem_clone).await;
-            (ip_clone, success)
-        });
-    }
+        prom_header(
+            &mut out,
+            "sentinel_alerts_total",
+            "counter",
+            "Failure alerts raised (after threshold and cooldown)",
+        );
+        prom_sample(&mut out, "sentinel_alerts_total", "", data.alerts);
 
-    let mut failed_ips = Vec::new();
-    let mut any_success = false;
+        prom_header(
+            &mut out,
+            "sentinel_recoveries_total",
+            "counter",
+            "Device recoveries",
+        );
+        prom_sample(&mut out, "sentinel_recoveries_total", "", data.recoveries);
 
-    while let Some(result) = tasks.join_next().await {
-        if let Ok((ip, success)) = result {
-            if success {
-                any_success = true;
-            } else {
-                failed_ips.push(ip);
-            }
+        prom_header(
+            &mut out,
+            "sentinel_device_up",
+            "gauge",
+            "1 if every check of the device passed in the last round",
+        );
+        for (labels, value) in &data.device_up {
+            prom_sample(&mut out, "sentinel_device_up", labels, value);
         }
-    }
-    (any_success, failed_ips)
-}
 
-async fn check_device_parallel(
-    device: &Device,
-    timeout_sec: u64,
-    semaphore: Arc<Semaphore>,
-) -> (bool, Vec<CheckFailure>) {
-    let mut tasks = tokio::task::JoinSet::new();
+        prom_header(
+            &mut out,
+            "sentinel_check_up",
+            "gauge",
+            "1 if the check satisfied its ip_policy in the last round",
+        );
+        for (labels, value) in &data.check_up {
+            prom_sample(&mut out, "sentinel_check_up", labels, value);
+        }
 
-    for check in &device.checks {
-        let check_clone = check.clone();
-        let ips_clone = device.ips.clone();
-        let sem_clone = semaphore.clone();
-        let to_sec = timeout_sec;
+        prom_header(
+            &mut out,
+            "sentinel_connect_duration_seconds",
+            "histogram",
+            "Latency of successful probes (TCP connect, HTTP response, average ping RTT)",
+        );
+        for (labels, histogram) in &data.connect_duration {
+            prom_histogram(
+                &mut out,
+                "sentinel_connect_duration_seconds",
+                labels,
+                histogram,
+            );
+        }
 
-        tasks.spawn(async move {
-            let (success, failed_ips) =
-                check_item_with_parallel_ip(&check_clone, &ips_clone, to_sec, sem_clone).await;
-            (check_clone, success, failed_ips)
-        });
-    }
+        prom_header(
+            &mut out,
+            "sentinel_connect_failures_total",
+            "counter",
+            "Failed or timed out probes",
+        );
+        for (labels, value) in &data.connect_failures {
+            prom_sample(&mut out, "sentinel_connect_failures_total", labels, value);
+        }
 
-    let mut failures = Vec::new();
+        prom_header(
+            &mut out,
+            "sentinel_dns_failures_total",
+            "counter",
+            "Rounds in which a hostname listed in ips did not resolve",
+        );
+        for (labels, value) in &data.dns_failures {
+            prom_sample(&mut out, "sentinel_dns_failures_total", labels, value);
+        }
 
-    while let Some(result) = tasks.join_next().await {
-        if let Ok((check, success, failed_ips)) = result {
-            if !success {
-                failures.push(CheckFailure {
-                    check_name: if check.name.is_empty() {
-                        format!("port:{}", check.port)
-                    } else {
-                        check.name.clone()
-                    },
-                    port: check.port,
-                    attempted_ips: failed_ips,
-                });
-            }
+        prom_header(
+            &mut out,
+            "sentinel_server_version_info",
+            "gauge",
+            "Server version announced by the last successful handshake",
+        );
+        for (labels, version) in &data.server_versions {
+            let labels = format!("{},{}", labels, prom_labels(&[("version", version)]));
+            prom_sample(&mut out, "sentinel_server_version_info", &labels, 1);
+        }
+
+        prom_header(
+            &mut out,
+            "sentinel_packet_loss_ratio",
+            "gauge",
+            "Fraction of echo requests lost in the last round (icmp checks)",
+        );
+        for (labels, value) in &data.packet_loss {
+            prom_sample(&mut out, "sentinel_packet_loss_ratio", labels, value);
+        }
+
+        prom_header(
+            &mut out,
+            "sentinel_notifications_total",
+            "counter",
+            "Alert deliveries per notifier, after retries",
+        );
+        for (labels, value) in &data.notifications {
+            prom_sample(&mut out, "sentinel_notifications_total", labels, value);
         }
+
+        out
     }
-    (failures.is_empty(), failures)
 }
 
-#[derive(Clone)]
-struct CheckFailure {
-    check_name: String,
-    port: u16,
-    attempted_ips: Vec<String>,
+fn prom_labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(k, v)| {
+            let escaped = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, escaped)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
-// ────────────────────────────────────────────────────────────
-// Alert Sending (WeCom Markdown - Clear Vertical Layout + Silent Mode + Retry Mechanism)
-// ────────────────────────────────────────────────────────────
+fn prom_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n",
+        name, help, name, kind
+    ));
+}
 
-async fn send_wechat_alert(webhook: &str, device: &Device, failures: &[CheckFailure]) {
-    let client = match reqwest::Client::builder()
-        .timeout(Duration::from_secs(10))
-        .build()
-    {
-        Ok(c) => c,
+fn prom_sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        out.push_str(&format!("{} {}\n", name, value));
+    } else {
+        out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+    }
+}
+
+fn prom_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    let bucket = format!("{}_bucket", name);
+    let mut cumulative = 0;
+    for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+        cumulative += count;
+        let le = format!("{}{}le=\"{}\"", labels, sep, bound);
+        prom_sample(out, &bucket, &le, cumulative);
+    }
+    let le = format!("{}{}le=\"+Inf\"", labels, sep);
+    prom_sample(out, &bucket, &le, histogram.count);
+    prom_sample(out, &format!("{}_sum", name), labels, histogram.sum);
+    prom_sample(out, &format!("{}_count", name), labels, histogram.count);
+}
+
+/// Minimal HTTP/1.1 listener: `GET /metrics` only, one request per connection
+async fn serve_metrics(listen: String, metrics: Arc<Metrics>) {
+    let listener = match TcpListener::bind(&listen).await {
+        Ok(l) => l,
         Err(e) => {
-            error!("Failed to create HTTP client: {}", e);
+            error!("✗ Metrics listener failed to bind {}: {}", listen, e);
             return;
         }
     };
+    info!("📈 Metrics endpoint: http://{}/metrics", listen);
 
-    let mut detail = String::from("```\n");
-
-    for (idx, failure) in failures.iter().enumerate() {
-        detail.push_str(&format!(
-            "┌─ 🔴 {} (Port：{})\n",
-            failure.check_name, failure.port
-        ));
-
-        let display_ips: Vec<&String> = failure.attempted_ips.iter().take(10).collect();
-        for (ip_idx, ip) in display_ips.iter().enumerate() {
-            let connector = if ip_idx == display_ips.len() - 1 {
-                "│  └─"
-            } else {
-                "│  ├─"
-            };
-            detail.push_str(&format!("{} ❌ {}\n", connector, ip));
-        }
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Metrics listener accept failed: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_metrics_request(stream, &metrics).await {
+                debug!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
 
-        if failure.attempted_ips.len() > 10 {
-            detail.push_str(&format!(
-                "│  └─ ... {} more IPs\n",
-                failure.attempted_ips.len() - 10
-            ));
-        }
+async fn handle_metrics_request(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let (read, mut write) = stream.split();
+    let mut reader = BufReader::new(read.take(8192));
 
-        if idx < failures.len() - 1 {
-            detail.push_str("│\n");
+    // Request line, then drain headers up to the blank line
+    let mut request_line = String::new();
+    timeout(Duration::from_secs(5), async {
+        reader.read_line(&mut request_line).await?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                return Ok::<_, std::io::Error>(());
+            }
         }
-    }
+    })
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"))??;
 
-    detail.push_str(&format!(
-        "└─ 📊 Stats：{} checks failed | {} IPs affected\n",
-        failures.len(),
-        failures
-            .iter()
-            .map(|f| f.attempted_ips.len())
-            .sum::<usize>()
-    ));
-    detail.push_str("```\n");
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
 
-    let priority_emoji = match device.priority.as_str() {
-        "critical" => "🔴",
-        "high" => "🟠",
-        "medium" => "🟡",
-        _ => "🔵",
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics.render(),
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "404 not found\n".to_string(),
+        ),
     };
 
-    let content = format!(
-        "{} **{}** Failure Alert\n\n\
-        > 📍 Location：{}\n\
-        > 💻 OS：{} | 🏷️ Group：{}\n\
-        > ⚠️ Priority：{}\n\n\
-        **Failure Details**：\n{}\n\
-        ---\n\
-        <font color=\"warning\">Recommendation：Check device power/network/service status</font>",
-        priority_emoji,
-        device.name,
-        device.location,
-        device.os,
-        device.group,
-        device.priority,
-        detail
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
     );
-
-    let payload = serde_json::json!({
-        "msgtype": "markdown",
-        "markdown": { "content": content }
-    });
-
-    // Silent sending + simple retry (avoid alert loss due to network jitter)
-    for attempt in 1..=3 {
-        match client.post(webhook).json(&payload).send().await {
-            Ok(resp) if resp.status().is_success() => return,
-            Ok(resp) => warn!(
-                "WeCom alert failed (attempt {}): HTTP {}",
-                attempt,
-                resp.status()
-            ),
-            Err(e) => warn!("WeCom alert failed (attempt {}): {}", attempt, e),
-        }
-
-        if attempt < 3 {
-            sleep(Duration::from_millis(500 * attempt as u64)).await;
-        }
-    }
+    write.write_all(response.as_bytes()).await?;
+    write.shutdown().await
 }
 
 // ────────────────────────────────────────────────────────────
-// Default Config Generation (Auto-create if config.toml not exists + Permission Protection)
+// Default Config Generation (`init` Subcommand + Permission Protection)
 // ────────────────────────────────────────────────────────────
 
 fn create_default_config(path: &str) -> Result<(), Box<dyn std::error::Error>> {
@@ -352,14 +3991,50 @@ interval = 15
 # Single TCP connection timeout (seconds), range 1-30, 3 for intranet, 10 for public network
 timeout = 3
 # Alert cooldown for same device (seconds), avoid spamming, recommended 300 (5min)
+# interval / timeout / alert_cooldown can be overridden per device and per check
 alert_cooldown = 300
-# WeCom robot webhook, supports ${WEBHOOK_URL} env var substitution
+# WeCom robot webhook (optional with [[notifier]]). Every string value may use ${VAR},
+# ${VAR:-default} or ${file:/run/secrets/name}; unset variables fail the config load
 # Production recommendation: webhook = "${WEBHOOK_URL}"
 webhook = "https://qyapi.weixin.qq.com/cgi-bin/webhook/send?key=YOUR_KEY_HERE"
 # Log level: debug | info | warn | error
 log_level = "info"
 # Max concurrent connections, adjust based on server performance, recommended = CPU cores * 10
 max_concurrent_connections = 100
+# Consecutive failed rounds before alerting / successful rounds before recovery
+# (raise failure_threshold for lossy links to suppress flapping; overridable per device)
+failure_threshold = 1
+recovery_threshold = 1
+# Alert state file (failed devices, cooldowns), saved after every round and restored on start
+# Set to "" to disable persistence
+state_file = "data/state.json"
+# Prometheus metrics endpoint (GET /metrics), e.g. "0.0.0.0:9898"; empty disables it
+metrics_listen = ""
+# Extra [[device]] files merged into this config (glob patterns relative to this file's
+# directory), e.g. one file per team. Device ids must be unique across all files.
+# include = ["devices.d/*.toml"]
+
+# ── Alert Destinations (optional) ────────────────────────────────────
+# settings.webhook above is a shortcut for one WeCom robot. Add [[notifier]] blocks to fan
+# every alert out to more destinations, each with its own retry policy.
+# type: wecom (WeCom robot markdown) | webhook (POST the alert event as JSON, or a template:
+#       method, headers and body may use {{ device.name }}, {{ failures | json }}, {{ duration }}...)
+#       dingtalk (DingTalk robot markdown; secret = "SEC..." for signing, keyword for keyword mode)
+#       feishu (Feishu/Lark bot interactive card; secret for signature verification)
+#       email (SMTP; host, port, tls = "none" | "starttls" | "tls", username, password,
+#              from, to, group_to = { database = [...] }, priority_to = { critical = [...] })
+# [[notifier]]
+# type = "webhook"
+# name = "ticketing"
+# url = "https://ops.example.com/api/alerts"
+# headers = { Authorization = "Bearer xxx" }
+# retry = { attempts = 5, backoff_ms = 1000 }
+
+# ── Group Settings (optional) ────────────────────────────────────────
+# digest = true merges all alerts of the group in one round into a single message
+# (per-device cooldown still applies). Useful when one switch takes down a whole rack.
+# [group.physical]
+# digest = true
 
 # ── Device Monitoring List (Flat structure, copy [[device]] to add) ───
 
@@ -385,6 +4060,12 @@ checks = [
 # group = "database"
 # priority = "critical"
 # ips = ["192.168.1.133", "192.168.1.128"]
+# # ip_policy: "any" (default, one IP up is enough) | "all" | { quorum = N } (degraded = warning)
+# ip_policy = "all"
+# # Overrides of the [settings] values; a check may override them again
+# interval = 5
+# timeout = 1
+# alert_cooldown = 60
 # os = "linux"
 # location = "Core Rack"
 # checks = [{ port = 6379, name = "Redis Service" }]
@@ -400,7 +4081,26 @@ checks = [
 # checks = [
 #     { port = 80, name = "HTTP" },
 #     { port = 443, name = "HTTPS" },
-#     { port = 3389, name = "RDP" }
+#     { port = 3389, name = "RDP" },
+#     # type = "http": request path/method, assert status and body (substring or regex)
+#     # options: path, method, expect_status, body_contains, body_regex, headers,
+#     #          https, host (Host header + SNI), insecure, follow_redirects
+#     { type = "http", port = 8080, name = "Health Check", path = "/healthz", expect_status = [200], body_contains = "ok" },
+#     # type = "tls": handshake + chain validation, warning when the cert expires within N days
+#     # options: sni, expiry_warn_days, ca_file (PEM of an internal CA), insecure (skip chain check)
+#     { type = "tls", port = 443, name = "HTTPS Cert", sni = "www.example.com", expiry_warn_days = 14 },
+#     # type = "banner": optionally send bytes, then expect a regex within N ms
+#     { type = "banner", port = 22, name = "SSH Banner", expect = "^SSH-2\\.0-", expect_within_ms = 2000 },
+#     # type = "redis": PING (+ AUTH with password/username), optional role = "master" | "replica"
+#     { type = "redis", port = 6379, name = "Redis", password = "xxx", role = "master" },
+#     # type = "mysql" | "postgres": read the server handshake (stuck in recovery / proxy without
+#     # backend fail); mysql username = password-less account to log in and quit cleanly
+#     { type = "mysql", port = 3306, name = "MySQL", username = "monitor" },
+#     { type = "postgres", port = 5432, name = "PostgreSQL" },
+#     # protocol = "udp": mode = "dns" (query = "...") | "ntp" | "raw" (send + optional expect)
+#     { protocol = "udp", port = 53, mode = "dns", name = "DNS", query = "www.example.com" },
+#     # type = "icmp": no port; ping count times, 100% loss fails, max_loss (%) / max_rtt_ms warn
+#     { type = "icmp", name = "Ping", count = 3, max_loss = 34, max_rtt_ms = 50 }
 # ]
 "#;
 
@@ -420,84 +4120,605 @@ checks = [
         }
     }
 
-    Ok(())
+    Ok(())
+}
+
+/// `init` subcommand: write the default template, refusing to overwrite unless forced
+fn init_config(config_path: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if Path::new(config_path).exists() && !force {
+        return Err(format!(
+            "{} already exists, pass --force to overwrite it",
+            config_path
+        )
+        .into());
+    }
+    create_default_config(config_path)?;
+
+    #[cfg(unix)]
+    {
+        println!("🔐 Config file permission set to 600 (read/write only for owner)");
+    }
+
+    println!("✅ Default config generated: {}", config_path);
+    println!("📝 Please edit config file, then start monitoring:");
+    println!("   1. Modify webhook to WeCom robot address");
+    println!("   2. Add devices to monitor");
+    println!("   3. Optional: Inject sensitive info via export WEBHOOK_URL=xxx");
+    println!("   4. port-sentinel-rs --config {} run", config_path);
+    println!();
+
+    // 🔧 Fix: Use ${{}} escape to avoid Rust macro parsing errors
+    println!("💡 Tip: ${{VAR}}, ${{VAR:-default}} and ${{file:/path}} in config values are expanded on load");
+    Ok(())
+}
+
+// ────────────────────────────────────────────────────────────
+// Config Loading + Env Var Substitution + URL Auto-cleanup + Parameter Validation
+// ────────────────────────────────────────────────────────────
+
+fn load_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let (mut config, lines) = read_config_file::<Config>(Path::new(path))?;
+
+    // 🔹 Merge `settings.include` files; every device remembers where it was defined
+    let mut origins: Vec<(PathBuf, usize)> = lines
+        .into_iter()
+        .map(|l| (PathBuf::from(path), l))
+        .collect();
+    config.sources.push(PathBuf::from(path));
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+    for pattern in config.settings.include.clone() {
+        let full = base.join(&pattern);
+        let mut files = glob::glob(&full.to_string_lossy())
+            .map_err(|e| format!("settings.include: invalid pattern '{}': {}", pattern, e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("settings.include: {}", e))?;
+        files.sort();
+        // Watch the directory too, so newly added files trigger a reload
+        if let Some(dir) = full
+            .ancestors()
+            .skip(1)
+            .find(|a| !a.to_string_lossy().contains(['*', '?', '[']))
+        {
+            config.sources.push(dir.to_path_buf());
+        }
+        for file in files {
+            if config.sources.contains(&file) {
+                continue;
+            }
+            let (included, lines) = read_config_file::<IncludeFile>(&file)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+            origins.extend(lines.into_iter().map(|l| (file.clone(), l)));
+            config.devices.extend(included.devices);
+            config.sources.push(file);
+        }
+    }
+    if config.devices.is_empty() {
+        return Err("no devices configured: add a [[device]] or settings.include".into());
+    }
+    let mut seen: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (device, (file, line)) in config.devices.iter().zip(&origins) {
+        seen.entry(&device.id)
+            .or_default()
+            .push(format!("{}:{}", file.display(), line));
+    }
+    if let Some((id, places)) = seen.iter().find(|(_, places)| places.len() > 1) {
+        return Err(format!("duplicate device id '{}': {}", id, places.join(", ")).into());
+    }
+
+    // 🔹 Clean up whitespace around webhook URLs
+    config.settings.webhook = config.settings.webhook.trim().to_string();
+    for notifier in &mut config.notifiers {
+        if let Some(url) = notifier.kind.url_mut() {
+            *url = url.trim().to_string();
+        }
+    }
+
+    // 🔹 Parameter validation (detect config errors on startup)
+    if config.settings.interval < 5 {
+        return Err("interval cannot be less than 5 seconds".into());
+    }
+    if config.settings.timeout < 1 || config.settings.timeout > 30 {
+        return Err("timeout should be between 1-30 seconds".into());
+    }
+
+    // 🔧 Fix: webhook empty check with friendly error message
+    if config.settings.webhook.is_empty() && config.notifiers.is_empty() {
+        return Err(
+            "no alert destination configured: set settings.webhook or add a [[notifier]]. \
+             Please set WEBHOOK_URL environment variable or edit config.toml"
+                .into(),
+        );
+    }
+    if !config.settings.webhook.is_empty() && !config.settings.webhook.starts_with("http") {
+        return Err(format!(
+            "webhook URL must start with http/https. Current value: '{}'. \
+             Please set WEBHOOK_URL environment variable or edit config.toml",
+            config.settings.webhook
+        )
+        .into());
+    }
+    for notifier in config.notifier_configs() {
+        if let Some(url) = notifier.kind.url() {
+            if !url.starts_with("http") {
+                return Err(format!(
+                    "notifier '{}': url must start with http/https. Current value: '{}'",
+                    notifier.name, url
+                )
+                .into());
+            }
+        }
+        if let NotifierKind::Email {
+            host,
+            from,
+            to,
+            group_to,
+            priority_to,
+            ..
+        } = &notifier.kind
+        {
+            if host.is_empty() {
+                return Err(format!("notifier '{}': host is required", notifier.name).into());
+            }
+            if from.parse::<Mailbox>().is_err() {
+                return Err(format!(
+                    "notifier '{}': invalid from address '{}'",
+                    notifier.name, from
+                )
+                .into());
+            }
+            let recipients = to
+                .iter()
+                .chain(group_to.values().flatten())
+                .chain(priority_to.values().flatten());
+            let mut has_recipient = false;
+            for address in recipients {
+                if address.parse::<Mailbox>().is_err() {
+                    return Err(format!(
+                        "notifier '{}': invalid recipient address '{}'",
+                        notifier.name, address
+                    )
+                    .into());
+                }
+                has_recipient = true;
+            }
+            if !has_recipient {
+                return Err(format!(
+                    "notifier '{}': at least one recipient (to/group_to/priority_to) is required",
+                    notifier.name
+                )
+                .into());
+            }
+        }
+        if let NotifierKind::Webhook {
+            url,
+            method,
+            headers,
+            body,
+        } = &notifier.kind
+        {
+            if reqwest::Method::from_bytes(method.to_uppercase().as_bytes()).is_err() {
+                return Err(
+                    format!("notifier '{}': invalid method '{}'", notifier.name, method).into(),
+                );
+            }
+
+            // Dry-run templates against a sample event so typos fail at load time, not on alert
+            if let Some(device) = config.devices.first() {
+                let sample_failures = device
+                    .checks
+                    .iter()
+                    .map(|check| CheckFailure {
+                        check_name: check.display_name(),
+                        port: check.port,
+                        attempted_ips: device.ips.clone(),
+                        reason: "connection refused".to_string(),
+                        severity: Severity::Error,
+                        kind: FailureKind::Probe,
+                        targets_up: 0,
+                        targets_total: device.ips.len(),
+                    })
+                    .collect();
+                let sample = AlertEvent {
+                    kind: AlertKind::Recovery,
+                    group: device.group.clone(),
+                    timestamp: 0,
+                    alerts: vec![DeviceAlert::failed(device.clone(), sample_failures, 0, 0)],
+                };
+                let ctx = template_context(&sample);
+                let templates = std::iter::once(url)
+                    .chain(headers.values())
+                    .chain(body.iter());
+                for template in templates {
+                    if let Err(e) = render_template(template, &ctx) {
+                        return Err(
+                            format!("notifier '{}': template error: {}", notifier.name, e).into(),
+                        );
+                    }
+                }
+            }
+        }
+        if notifier.retry.attempts == 0 {
+            return Err(format!(
+                "notifier '{}': retry.attempts must be at least 1",
+                notifier.name
+            )
+            .into());
+        }
+    }
+
+    if config.settings.max_concurrent_connections == 0 {
+        return Err("max_concurrent_connections must be greater than 0".into());
+    }
+
+    if config.settings.failure_threshold == 0 || config.settings.recovery_threshold == 0 {
+        return Err("failure_threshold and recovery_threshold must be at least 1".into());
+    }
+    if let Some(device) = config
+        .devices
+        .iter()
+        .find(|d| d.failure_threshold == Some(0) || d.recovery_threshold == Some(0))
+    {
+        return Err(format!(
+            "device '{}': failure_threshold and recovery_threshold must be at least 1",
+            device.id
+        )
+        .into());
+    }
+    for device in &config.devices {
+        validate_schedule(device.interval, device.timeout)
+            .map_err(|e| format!("device '{}': {}", device.id, e))?;
+        if device.ips.is_empty() {
+            return Err(format!("device '{}': ips must not be empty", device.id).into());
+        }
+        for ip in &device.ips {
+            validate_address(ip).map_err(|e| format!("device '{}': {}", device.id, e))?;
+        }
+        let static_ips = device.ips.iter().all(|ip| parse_ip(ip, 0).is_ok());
+        for check in &device.checks {
+            // Hostnames may resolve to more addresses, so only a pure IP list bounds the quorum
+            if let Some(IpPolicy::Quorum(n)) = check.ip_policy.or(device.ip_policy) {
+                if n == 0 || (static_ips && n > device.ips.len()) {
+                    return Err(format!(
+                        "device '{}' check '{}': quorum must be 1..={}, got {}",
+                        device.id,
+                        check.display_name(),
+                        device.ips.len(),
+                        n
+                    )
+                    .into());
+                }
+            }
+            validate_schedule(check.interval, check.timeout)
+                .and_then(|_| validate_check(check))
+                .map_err(|e| {
+                    format!(
+                        "device '{}' check '{}': {}",
+                        device.id,
+                        check.display_name(),
+                        e
+                    )
+                })?;
+        }
+    }
+
+    Ok(config)
 }
 
-/// Ensure config file exists, create default config and exit to prompt user edit if not exists
-fn ensure_config_exists(config_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let path = Path::new(config_path);
+/// Read one config file: expand variables in every string value, then deserialize.
+/// Also returns the line of each `[[device]]` id.
+fn read_config_file<T: serde::de::DeserializeOwned>(
+    path: &Path,
+) -> Result<(T, Vec<usize>), Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path)?;
+    let lines = toml::from_str::<DeviceIdSpans>(&content)?
+        .device
+        .iter()
+        .map(|d| content[..d.id.span().start].matches('\n').count() + 1)
+        .collect();
 
-    // Config file exists, return directly
-    if path.exists() {
-        info!("✓ Config file detected: {}", config_path);
-        return Ok(config_path.to_string());
+    // 🔹 Variable expansion on parsed string values (comments and keys are left alone)
+    let mut value: toml::Value = toml::from_str(&content)?;
+    expand_value(&mut value, "")?;
+    match value.try_into() {
+        Ok(config) => Ok((config, lines)),
+        Err(e) => {
+            // The expanded tree has no positions: if the text as written fails the same way,
+            // report that error instead so it points at a line
+            match toml::from_str::<T>(&content) {
+                Err(raw) if raw.message() == e.message() => Err(raw.into()),
+                _ => Err(e.into()),
+            }
+        }
     }
+}
 
-    // Create default config if not exists
-    warn!(
-        "⚙️  Config file not found, creating default config: {}",
-        config_path
-    );
-    create_default_config(config_path)?;
-
-    #[cfg(unix)]
-    {
-        println!("🔐 Config file permission set to 600 (read/write only for owner)");
+/// Same bounds as `settings.interval` / `settings.timeout` for the per-device/check overrides
+fn validate_schedule(interval: Option<u64>, timeout: Option<u64>) -> Result<(), String> {
+    if let Some(interval) = interval.filter(|i| *i < 5) {
+        return Err(format!(
+            "interval cannot be less than 5 seconds, got {}",
+            interval
+        ));
     }
-
-    println!("✅ Default config generated: {}", config_path);
-    println!("📝 Please edit config file and restart program:");
-    println!("   1. Modify webhook to WeCom robot address");
-    println!("   2. Add devices to monitor");
-    println!("   3. Optional: Inject sensitive info via export WEBHOOK_URL=xxx");
-    println!();
-
-    // 🔧 Fix: Use ${{}} escape to avoid Rust macro parsing errors
-    println!("💡 Tip: ${{WEBHOOK_URL}} in config will be automatically replaced by env var");
-
-    std::process::exit(0);
+    if let Some(timeout) = timeout.filter(|t| !(1..=30).contains(t)) {
+        return Err(format!(
+            "timeout should be between 1-30 seconds, got {}",
+            timeout
+        ));
+    }
+    Ok(())
 }
 
-// ────────────────────────────────────────────────────────────
-// Config Loading + Env Var Substitution + URL Auto-cleanup + Parameter Validation
-// ────────────────────────────────────────────────────────────
-
-fn load_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
-    let content = fs::read_to_string(path)?;
+/// An `ips` entry must be an IP literal (IPv6 link-local with its zone) or a hostname
+fn validate_address(entry: &str) -> Result<(), String> {
+    let looks_like_ip =
+        entry.contains([':', '%']) || entry.chars().all(|c| c.is_ascii_digit() || c == '.');
+    if !looks_like_ip {
+        let valid_hostname = entry.len() <= 253
+            && entry.trim_end_matches('.').split('.').all(|label| {
+                (1..=63).contains(&label.len())
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid_hostname {
+            return Err(format!("invalid IP address or hostname '{}'", entry));
+        }
+        return Ok(());
+    }
+    match parse_ip(entry, 0)? {
+        SocketAddr::V6(v6) if v6.ip().segments()[0] & 0xffc0 == 0xfe80 && v6.scope_id() == 0 => {
+            Err(format!(
+                "link-local address '{}' needs a zone, e.g. '{}%eth0'",
+                entry, entry
+            ))
+        }
+        _ => Ok(()),
+    }
+}
 
-    // 🔹 Env var substitution: support ${WEBHOOK_URL} syntax
-    let content = env::var("WEBHOOK_URL")
-        .map(|val| content.replace("${WEBHOOK_URL}", &val))
-        .unwrap_or(content);
+/// Expand `${...}` in every string of the parsed config; errors name the offending key
+fn expand_value(value: &mut toml::Value, path: &str) -> Result<(), String> {
+    match value {
+        toml::Value::String(text) => {
+            *text = expand_vars(text).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                expand_value(item, &format!("{}[{}]", path, i))?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                expand_value(item, &path)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
 
-    let mut config: Config = toml::from_str(&content)?;
+/// `${NAME}` env var (error if unset), `${NAME:-default}` (default if unset or empty),
+/// `${file:/path}` file content without trailing newline, `$${` for a literal `${`
+fn expand_vars(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated '${{' in '{}'", text))?;
+        let expr = &after[..end];
+        rest = &after[end + 1..];
 
-    // 🔹 Clean up whitespace around webhook
-    config.settings.webhook = config.settings.webhook.trim().to_string();
+        if let Some(file) = expr.strip_prefix("file:") {
+            let content = fs::read_to_string(file)
+                .map_err(|e| format!("can't read secret file '{}': {}", file, e))?;
+            out.push_str(content.trim_end_matches(['\r', '\n']));
+            continue;
+        }
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        let valid_name = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(format!("invalid variable '${{{}}}'", expr));
+        }
+        match (env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => out.push_str(default),
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => return Err(format!("environment variable {} is not set", name)),
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
 
-    // 🔹 Parameter validation (detect config errors on startup)
-    if config.settings.interval < 5 {
-        return Err("interval cannot be less than 5 seconds".into());
+/// Reject probe options that would fail on every round
+fn validate_check(check: &CheckItem) -> Result<(), String> {
+    if check.kind == CheckKind::Icmp {
+        if check.port != 0 {
+            return Err("icmp check has no port, remove port".to_string());
+        }
+        if !(1..=100).contains(&check.icmp.count()) {
+            return Err(format!("count must be 1..=100, got {}", check.icmp.count()));
+        }
+        if let Some(max_loss) = check.icmp.max_loss.filter(|loss| *loss > 100) {
+            return Err(format!("max_loss is a percentage, got {}", max_loss));
+        }
+    } else if check.port == 0 {
+        return Err("port is required".to_string());
     }
-    if config.settings.timeout < 1 || config.settings.timeout > 30 {
-        return Err("timeout should be between 1-30 seconds".into());
+    if check.protocol == Protocol::Udp {
+        if check.kind != CheckKind::Tcp {
+            return Err(format!(
+                "type = \"{}\" is TCP-only and can't be combined with protocol = \"udp\"",
+                check.kind.as_str()
+            ));
+        }
+        match check.udp.mode() {
+            UdpMode::Raw if check.banner.send.is_none() => {
+                return Err("udp raw mode requires send".to_string());
+            }
+            UdpMode::Raw => {
+                if let Some(pattern) = &check.banner.expect {
+                    regex::bytes::Regex::new(pattern)
+                        .map_err(|e| format!("invalid expect: {}", e))?;
+                }
+            }
+            UdpMode::Dns => {
+                let query = check.udp.query.as_deref().unwrap_or(".");
+                if query.split('.').any(|label| label.len() > 63) {
+                    return Err(format!("invalid dns query '{}'", query));
+                }
+            }
+            UdpMode::Ntp => {}
+        }
     }
-
-    // 🔧 Fix: webhook empty check with friendly error message
-    if config.settings.webhook.is_empty() || !config.settings.webhook.starts_with("http") {
-        return Err(format!(
-            "webhook URL is required and must start with http/https. Current value: '{}'. \
-             Please set WEBHOOK_URL environment variable or edit config.toml",
-            config.settings.webhook
-        )
-        .into());
+    if check.kind == CheckKind::Http {
+        let http = &check.http;
+        if !http.path().starts_with('/') {
+            return Err(format!("path must start with '/', got '{}'", http.path()));
+        }
+        if reqwest::Method::from_bytes(http.method().as_bytes()).is_err() {
+            return Err(format!("invalid method '{}'", http.method()));
+        }
+        if let Some(status) = http.expect_status.iter().find(|s| !(100..600).contains(*s)) {
+            return Err(format!("invalid expect_status {}", status));
+        }
+        if let Some(pattern) = &http.body_regex {
+            Regex::new(pattern).map_err(|e| format!("invalid body_regex: {}", e))?;
+        }
+        for (key, value) in &http.headers {
+            if reqwest::header::HeaderName::from_bytes(key.as_bytes()).is_err()
+                || reqwest::header::HeaderValue::from_str(value).is_err()
+            {
+                return Err(format!("invalid header '{}'", key));
+            }
+        }
+    }
+    if check.kind == CheckKind::Tls {
+        if let Some(sni) = &check.tls.sni {
+            ServerName::try_from(sni.as_str())
+                .map_err(|e| format!("invalid sni '{}': {}", sni, e))?;
+        }
+        if let Some(path) = &check.tls.ca_file {
+            load_ca_file(path)?;
+        }
+    }
+    if check.kind == CheckKind::Banner {
+        let pattern = check
+            .banner
+            .expect
+            .as_deref()
+            .ok_or("banner check requires expect")?;
+        regex::bytes::Regex::new(pattern).map_err(|e| format!("invalid expect: {}", e))?;
+    }
+    if check.kind == CheckKind::Redis {
+        if let Some(role) = &check.redis.role {
+            if !["master", "replica"].contains(&role.to_lowercase().as_str()) {
+                return Err(format!("role must be master or replica, got '{}'", role));
+            }
+        }
+        if check.auth.username.is_some() && check.auth.password.is_none() {
+            return Err("username requires password".to_string());
+        }
     }
+    if check.kind == CheckKind::Mysql && check.auth.password.is_some() {
+        return Err("mysql check logs in without password, remove password".to_string());
+    }
+    Ok(())
+}
 
-    if config.settings.max_concurrent_connections == 0 {
-        return Err("max_concurrent_connections must be greater than 0".into());
+/// Load persisted alert state (if enabled) and prune devices removed from config
+fn restore_alert_state(state_file: &str, config: &Config) -> AlertState {
+    if state_file.is_empty() || !Path::new(state_file).exists() {
+        return AlertState::new();
     }
 
-    Ok(config)
+    let mut state = match AlertState::load(state_file) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!("Ignoring unreadable alert state {}: {}", state_file, e);
+            return AlertState::new();
+        }
+    };
+
+    let device_ids: HashSet<&str> = config.devices.iter().map(|d| d.id.as_str()).collect();
+    let pruned = state.retain_devices(&device_ids);
+    info!(
+        "✓ Alert state restored: {} ({} failed devices, {} stale entries pruned)",
+        state_file,
+        state.is_failed.len(),
+        pruned
+    );
+    state
+}
+
+/// Modification times of the config sources (`None` where unreadable), compared as a whole
+fn config_modified(sources: &[PathBuf]) -> Vec<Option<std::time::SystemTime>> {
+    sources
+        .iter()
+        .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Sleep for `wait`, returning `true` early when SIGHUP arrives or any config source's mtime
+/// changes (polled every 2s)
+async fn wait_for_reload(
+    wait: Duration,
+    config_path: &str,
+    sources: &[PathBuf],
+    config_mtime: &mut Vec<Option<std::time::SystemTime>>,
+    sighup: &mut tokio::signal::unix::Signal,
+) -> bool {
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut poll = tokio::time::interval(Duration::from_secs(2));
+    poll.tick().await;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => return false,
+            _ = sighup.recv() => {
+                info!("🔄 SIGHUP received, reloading {}", config_path);
+                *config_mtime = config_modified(sources);
+                return true;
+            }
+            _ = poll.tick() => {
+                let mtime = config_modified(sources);
+                if let Some((source, _)) = sources
+                    .iter()
+                    .zip(mtime.iter().zip(config_mtime.iter()))
+                    .find(|(_, (new, old))| new.is_some() && new != old)
+                {
+                    info!("🔄 {} changed, reloading", source.display());
+                    *config_mtime = mtime;
+                    return true;
+                }
+            }
+        }
+    }
 }
 
 // ────────────────────────────────────────────────────────────
@@ -536,33 +4757,168 @@ fn init_logging(log_level: &str) {
 }
 
 // ────────────────────────────────────────────────────────────
-// Main Program Entry (Graceful Startup + Monitoring Loop + Signal Handling)
+// Command-Line Interface (Config Path + run / check / validate / init)
 // ────────────────────────────────────────────────────────────
 
-#[tokio::main]
-async fn main() {
-    // 🔹 1. Ensure config exists first (to get log level from config)
-    let config_path = match ensure_config_exists("config.toml") {
-        Ok(path) => path,
-        Err(e) => {
-            eprintln!("✗ Config initialization failed: {}", e);
-            std::process::exit(1);
-        }
-    };
+#[derive(Parser)]
+#[command(
+    name = "port-sentinel-rs",
+    version,
+    about = "Async TCP/UDP/ICMP port monitoring with alerting"
+)]
+struct Cli {
+    /// Config file path
+    #[arg(short, long, global = true, default_value = "config.toml")]
+    config: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    // 🔹 2. Load config to get log level
-    let config_for_log = match load_config(&config_path) {
+#[derive(Subcommand)]
+enum Command {
+    /// Monitor continuously and send alerts (default)
+    Run,
+    /// Run a single detection round without alerting; exit code 1 if any check fails
+    Check,
+    /// Parse and validate the config, then exit
+    Validate,
+    /// Write the default config template
+    Init {
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Load the config or exit with a hint, for subcommands that need one
+fn load_config_or_exit(config_path: &str) -> Config {
+    if !Path::new(config_path).exists() {
+        eprintln!(
+            "✗ Config file not found: {} (create one with `port-sentinel-rs --config {} init`)",
+            config_path, config_path
+        );
+        std::process::exit(1);
+    }
+    match load_config(config_path) {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("✗ Failed to load config for logging: {}", e);
+            eprintln!("✗ Config load failed: {}", e);
             std::process::exit(1);
         }
+    }
+}
+
+/// `validate` subcommand: everything `run` checks at startup, without probing
+fn validate_command(config_path: &str) -> i32 {
+    let config = load_config_or_exit(config_path);
+    if let Err(e) = build_notifiers(&config) {
+        eprintln!("✗ Notifier initialization failed: {}", e);
+        return 1;
+    }
+    println!(
+        "✓ Config valid: {} ({} devices, {} checks, {} notifiers)",
+        config_path,
+        config.devices.len(),
+        config.devices.iter().map(|d| d.checks.len()).sum::<usize>(),
+        config.notifiers.len()
+    );
+    0
+}
+
+/// `check` subcommand: one round against every device, printed to stdout. Thresholds,
+/// cooldown and notifiers are not involved; warnings alone keep the exit code at 0.
+async fn check_command(config_path: &str) -> i32 {
+    let config = load_config_or_exit(config_path);
+    init_logging(&config.settings.log_level);
+    let semaphore = Arc::new(Semaphore::new(config.settings.max_concurrent_connections));
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for (position, device) in config.devices.iter().enumerate() {
+        let dev = device.clone();
+        let to_sec = config.settings.timeout;
+        let sem = semaphore.clone();
+        let due: Vec<usize> = (0..dev.checks.len()).collect();
+        tasks.spawn(async move {
+            let results = check_device_parallel(&dev, &due, to_sec, sem).await;
+            (position, dev, results)
+        });
+    }
+    // Tasks finish in any order, print devices and checks as they appear in the config
+    let mut finished = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        if let Ok((position, device, mut results)) = result {
+            results.sort_by_key(|r| r.index);
+            finished.push((position, device, results));
+        }
+    }
+    finished.sort_by_key(|(position, _, _)| *position);
+    let rounds: Vec<(Device, Vec<CheckResult>)> = finished
+        .into_iter()
+        .map(|(_, device, results)| (device, results))
+        .collect();
+
+    let (mut failed, mut warned) = (0, 0);
+    for (device, results) in &rounds {
+        let failures: Vec<CheckFailure> = results.iter().filter_map(|r| r.failure()).collect();
+        match failures.iter().map(|f| f.severity).max() {
+            None => println!("✅ {} ({})", device.name, device.id),
+            Some(severity) => {
+                if severity == Severity::Error {
+                    failed += 1;
+                } else {
+                    warned += 1;
+                }
+                println!("{} {} ({})", severity.markers().0, device.name, device.id);
+                for line in render_failure_tree(&failures).lines() {
+                    println!("   {}", line);
+                }
+            }
+        }
+    }
+    println!();
+    println!(
+        "📊 {} devices | {} failed | {} warnings",
+        rounds.len(),
+        failed,
+        warned
+    );
+    i32::from(failed > 0)
+}
+
+// ────────────────────────────────────────────────────────────
+// Main Program Entry (Graceful Startup + Monitoring Loop + Signal Handling)
+// ────────────────────────────────────────────────────────────
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let code = match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            run(&cli.config).await;
+            0
+        }
+        Command::Check => check_command(&cli.config).await,
+        Command::Validate => validate_command(&cli.config),
+        Command::Init { force } => match init_config(&cli.config, force) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("✗ Config initialization failed: {}", e);
+                1
+            }
+        },
     };
+    std::process::exit(code);
+}
 
-    // 🔹 3. Initialize logging system with config log level
+/// `run` subcommand: the monitoring daemon
+async fn run(config_path: &str) {
+    // 🔹 1. Load config first to get log level (missing file: hint to run `init`)
+    let config_for_log = load_config_or_exit(config_path);
+
+    // 🔹 2. Initialize logging system with config log level
     init_logging(&config_for_log.settings.log_level);
 
-    // 🔹 4. Print startup banner
+    // 🔹 3. Print startup banner
     println!();
     println!("╔══════════════════════════════════════════════════════════╗");
     println!("║     🚀 port-sentinel-rs v2.2.6 (High Performance)         ║");
@@ -575,14 +4931,18 @@ async fn main() {
         Local::now().format("%Y-%m-%d %H:%M:%S")
     );
 
-    // 🔹 5. Reload full config (with validation)
-    let config = match load_config(&config_path) {
+    // 🔹 4. Reload full config (with validation)
+    let mut config = match load_config(config_path) {
         Ok(c) => {
             info!("✓ Config loaded successfully");
             info!("  ├─ Device count: {}", c.devices.len());
             info!("  ├─ Polling interval: {}s", c.settings.interval);
             info!("  ├─ Connection timeout: {}s", c.settings.timeout);
             info!("  ├─ Alert cooldown: {}s", c.settings.alert_cooldown);
+            info!(
+                "  ├─ Failure/recovery threshold: {}/{} rounds",
+                c.settings.failure_threshold, c.settings.recovery_threshold
+            );
             info!(
                 "  └─ Concurrent limit: {} connections",
                 c.settings.max_concurrent_connections
@@ -596,30 +4956,58 @@ async fn main() {
         }
     };
 
-    // 🔹 6. Initialize shared resources
-    let semaphore = Arc::new(Semaphore::new(config.settings.max_concurrent_connections));
-    let alert_state = Arc::new(Mutex::new(AlertState::new()));
-    let webhook = config.settings.webhook.clone();
-    let timeout_sec = config.settings.timeout;
-    let interval_sec = config.settings.interval;
-    let cooldown_sec = config.settings.alert_cooldown;
+    // 🔹 5. Initialize shared resources
+    let mut semaphore = Arc::new(Semaphore::new(config.settings.max_concurrent_connections));
+    let alert_state = Arc::new(Mutex::new(restore_alert_state(
+        &config.settings.state_file,
+        &config,
+    )));
+    let mut notifiers = match build_notifiers(&config) {
+        Ok(n) => n,
+        Err(e) => {
+            error!("✗ Notifier initialization failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let metrics = Arc::new(Metrics::new());
+    if !config.settings.metrics_listen.is_empty() {
+        tokio::spawn(serve_metrics(
+            config.settings.metrics_listen.clone(),
+            metrics.clone(),
+        ));
+    }
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("✗ SIGHUP handler installation failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut config_mtime = config_modified(&config.sources);
 
-    // 🔹 7. Graceful shutdown signal handling
+    // 🔹 6. Graceful shutdown signal handling
     let shutdown_signal = async {
         let _ = tokio::signal::ctrl_c().await;
         println!();
         warn!("🛑 Shutdown signal received, exiting gracefully...");
     };
 
-    // 🔹 8. Main monitoring loop (supports long-term stable operation)
+    // 🔹 7. Main monitoring loop (supports long-term stable operation)
     let monitor_loop = async {
         let mut round = 0u64;
         let mut total_alerts = 0u64;
         let mut recovered_count = 0u64;
+        // Per-check next run time and latest result, keyed by device id (reset on reload)
+        let mut next_due: HashMap<String, Vec<Instant>> = HashMap::new();
+        let mut last_results: HashMap<String, BTreeMap<usize, CheckResult>> = HashMap::new();
 
         loop {
             round += 1;
             let round_start = Instant::now();
+            let timeout_sec = config.settings.timeout;
+            let interval_sec = config.settings.interval;
+            let cooldown_sec = config.settings.alert_cooldown;
+            let state_file = config.settings.state_file.clone();
 
             // Print statistics separator every 10 rounds
             if round.is_multiple_of(10) {
@@ -628,71 +5016,185 @@ async fn main() {
 
             let mut tasks = tokio::task::JoinSet::new();
 
-            // 🔹 Submit all device detection tasks concurrently
+            // 🔹 Submit detection tasks of every device with due checks concurrently
             for device in &config.devices {
+                let schedule = next_due
+                    .entry(device.id.clone())
+                    .or_insert_with(|| vec![round_start; device.checks.len()]);
+                let due: Vec<usize> = (0..device.checks.len())
+                    .filter(|&i| schedule[i] <= round_start)
+                    .collect();
+                if due.is_empty() {
+                    continue;
+                }
+                // Step from the previous due time so checks sharing a cadence stay aligned
+                for &i in &due {
+                    let every = device.check_interval(&device.checks[i], interval_sec);
+                    schedule[i] = (schedule[i] + Duration::from_secs(every)).max(round_start);
+                }
+
                 let dev = device.clone();
                 let to_sec = timeout_sec;
                 let sem = semaphore.clone();
 
                 tasks.spawn(async move {
-                    let (ok, failures) = check_device_parallel(&dev, to_sec, sem).await;
-                    (dev, ok, failures)
+                    let results = check_device_parallel(&dev, &due, to_sec, sem).await;
+                    (dev, results)
                 });
             }
+            let checked = tasks.len();
 
             // 🔹 Collect detection results and aggregate failures by group
-            let mut group_failures: HashMap<String, Vec<(Device, Vec<CheckFailure>)>> =
-                HashMap::new();
+            let mut group_failures: HashMap<String, Vec<DeviceFailures>> = HashMap::new();
+            let mut group_recoveries: HashMap<String, Vec<DeviceAlert>> = HashMap::new();
+            let mut cooldowns: HashMap<String, u64> = HashMap::new();
 
             while let Some(result) = tasks.join_next().await {
-                if let Ok((device, is_ok, failures)) = result {
+                if let Ok((device, results)) = result {
+                    // Checks that weren't due keep their latest result
+                    let fresh: HashSet<usize> = results.iter().map(|r| r.index).collect();
+                    let latest = last_results.entry(device.id.clone()).or_default();
+                    latest.extend(results.into_iter().map(|r| (r.index, r)));
+                    let results: Vec<&CheckResult> = latest.values().collect();
+                    metrics.record_device(&device, &results, &fresh);
+
+                    let mut failures = Vec::new();
+                    let mut check_cooldowns = Vec::new();
+                    let mut failing = BTreeSet::new();
+                    for result in &results {
+                        if let Some(failure) = result.failure() {
+                            failures.push(failure);
+                            check_cooldowns
+                                .push(device.check_cooldown(&result.check, cooldown_sec));
+                            failing.insert(result.index);
+                        }
+                    }
+                    let is_ok = failures.is_empty();
+
+                    let now_ts = Local::now().timestamp();
+                    let mut state = alert_state.lock().await;
+
+                    // A round only counts toward the thresholds when a check behind its outcome
+                    // actually ran; cached results never add to a streak
                     if !is_ok {
-                        group_failures
-                            .entry(device.group.clone())
-                            .or_default()
-                            .push((device, failures));
+                        // Only count as failed after enough consecutive failed rounds
+                        let threshold = device.failure_threshold(&config.settings);
+                        if state.record_failed_round(
+                            &device.id, &failures, &failing, &fresh, now_ts, threshold,
+                        ) {
+                            // The most urgent failing check sets the pace of repeat alerts
+                            let cooldown =
+                                check_cooldowns.into_iter().min().unwrap_or(cooldown_sec);
+                            cooldowns.insert(device.id.clone(), cooldown);
+                            group_failures
+                                .entry(device.group.clone())
+                                .or_default()
+                                .push((device, failures));
+                        } else {
+                            debug!(
+                                "⏳ Device failing, below threshold {}: {} ({})",
+                                threshold, device.name, device.id
+                            );
+                        }
                     } else {
-                        // Device recovered: clear alert state and count
-                        let mut state = alert_state.lock().await;
-                        if state.mark_recovered(&device.id) {
+                        // Device recovered: clear alert state, count and queue recovery notice
+                        let threshold = device.recovery_threshold(&config.settings);
+                        if let Some(recovery) =
+                            state.record_ok_round(&device.id, &fresh, now_ts, threshold)
+                        {
                             recovered_count += 1;
-                            info!("✅ Device recovered: {} ({})", device.name, device.id);
+                            info!(
+                                "✅ Device recovered: {} ({}) after {}",
+                                device.name,
+                                device.id,
+                                format_duration(recovery.duration_secs())
+                            );
+                            group_recoveries
+                                .entry(device.group.clone())
+                                .or_default()
+                                .push(DeviceAlert::recovered(device, recovery));
                         }
                     }
                 }
             }
 
-            // 🔹 Send alerts (with cooldown control + silent mode)
+            // 🔹 Send recovery notifications (no cooldown: each outage ends exactly once)
             let now_ts = Local::now().timestamp();
-            let mut state = alert_state.lock().await;
-            let mut new_alerts = 0u64;
+            let mut events = Vec::new();
+            for (group, recovered) in group_recoveries {
+                let digest = config.digest_enabled(&group);
+                events.extend(group_events(
+                    AlertKind::Recovery,
+                    &group,
+                    recovered,
+                    digest,
+                    now_ts,
+                ));
+            }
 
-            for failed_list in group_failures.values() {
-                for (device, failures) in failed_list {
-                    // Pass actual failure state to should_alert (fixed core bug)
-                    if state.should_alert(&device.id, true, now_ts, cooldown_sec) {
-                        new_alerts += 1;
-                        total_alerts += 1;
-
-                        // Temporarily release lock to avoid deadlock, re-acquire after sending
-                        let webhook_clone = webhook.clone();
-                        let dev_clone = device.clone();
-                        let failures_clone = failures.clone();
-
-                        drop(state);
-                        send_wechat_alert(&webhook_clone, &dev_clone, &failures_clone).await;
-                        state = alert_state.lock().await;
+            // 🔹 Decide alerts per device (cooldown control), then send without holding the lock
+            let mut new_alerts = 0u64;
+            {
+                let mut state = alert_state.lock().await;
+                for (group, failed_list) in &group_failures {
+                    let mut alerting = Vec::new();
+                    for (device, failures) in failed_list {
+                        // Pass actual failure state to should_alert (fixed core bug)
+                        let severity = failures
+                            .iter()
+                            .map(|f| f.severity)
+                            .max()
+                            .unwrap_or_default();
+                        let cooldown = cooldowns.get(&device.id).copied().unwrap_or(cooldown_sec);
+                        if state.should_alert(&device.id, true, severity, now_ts, cooldown) {
+                            let first_failure_ts = state
+                                .first_failure
+                                .get(&device.id)
+                                .copied()
+                                .unwrap_or(now_ts);
+                            alerting.push(DeviceAlert::failed(
+                                device.clone(),
+                                failures.clone(),
+                                first_failure_ts,
+                                now_ts,
+                            ));
+                        }
                     }
+                    new_alerts += alerting.len() as u64;
+
+                    // Digest groups get one message per round, others one per device
+                    let digest = config.digest_enabled(group);
+                    events.extend(group_events(
+                        AlertKind::Failure,
+                        group,
+                        alerting,
+                        digest,
+                        now_ts,
+                    ));
+                }
+            }
+            total_alerts += new_alerts;
+
+            // 🔹 Fan events out to all notifiers
+            for event in events {
+                dispatch(&notifiers, event, &metrics).await;
+            }
+
+            // 🔹 Persist alert state so restarts don't forget ongoing outages
+            if !state_file.is_empty() {
+                if let Err(e) = alert_state.lock().await.save(&state_file) {
+                    warn!("Failed to save alert state to {}: {}", state_file, e);
                 }
             }
 
+            metrics.record_round(round_start.elapsed(), total_alerts, recovered_count);
             let elapsed = round_start.elapsed().as_secs();
 
             // 🔹 Output current round results
             if group_failures.is_empty() {
                 info!(
-                    "✓ Round {:>3} | All devices normal | Elapsed: {}s",
-                    round, elapsed
+                    "✓ Round {:>3} | {} devices checked, all normal | Elapsed: {}s",
+                    round, checked, elapsed
                 );
             } else {
                 warn!(
@@ -714,15 +5216,68 @@ async fn main() {
                 println!();
             }
 
-            // 🔹 Smart wait: ensure stable polling interval (subtract detection time)
-            if elapsed < interval_sec {
-                sleep(Duration::from_secs(interval_sec - elapsed)).await;
+            // 🔹 Smart wait: sleep until the next check is due (each device/check keeps its own
+            // interval); if detection overran an interval, that check runs again immediately
+            let wait = next_due
+                .values()
+                .flatten()
+                .min()
+                .map(|due| due.saturating_duration_since(Instant::now()))
+                .unwrap_or(Duration::from_secs(interval_sec));
+            if !wait_for_reload(
+                wait,
+                config_path,
+                &config.sources,
+                &mut config_mtime,
+                &mut sighup,
+            )
+            .await
+            {
+                continue;
+            }
+
+            // 🔹 Hot reload: swap in the new config only if it fully validates
+            let reloaded = load_config(config_path).and_then(|c| {
+                let n = build_notifiers(&c)?;
+                Ok((c, n))
+            });
+            match reloaded {
+                Ok((new_config, new_notifiers)) => {
+                    let device_ids: HashSet<&str> =
+                        new_config.devices.iter().map(|d| d.id.as_str()).collect();
+                    let pruned = alert_state.lock().await.retain_devices(&device_ids);
+                    metrics.retain_config(&new_config.devices);
+                    if new_config.settings.max_concurrent_connections
+                        != config.settings.max_concurrent_connections
+                    {
+                        semaphore = Arc::new(Semaphore::new(
+                            new_config.settings.max_concurrent_connections,
+                        ));
+                    }
+                    if new_config.settings.metrics_listen != config.settings.metrics_listen
+                        || new_config.settings.log_level != config.settings.log_level
+                    {
+                        warn!("⚠ metrics_listen / log_level changes take effect after restart");
+                    }
+                    info!(
+                        "🔄 Config reloaded: {} devices ({} stale alert states pruned)",
+                        new_config.devices.len(),
+                        pruned
+                    );
+                    config_mtime = config_modified(&new_config.sources);
+                    // Checks may have moved or changed: run everything again right away
+                    next_due.clear();
+                    last_results.clear();
+                    alert_state.lock().await.outage_checks.clear();
+                    config = new_config;
+                    notifiers = new_notifiers;
+                }
+                Err(e) => error!("✗ Config reload failed, keeping previous config: {}", e),
             }
-            // If detection takes longer than interval, start next round immediately (avoid backlog)
         }
     };
 
-    // 🔹 9. Wait concurrently: monitoring loop or shutdown signal (whichever comes first)
+    // 🔹 8. Wait concurrently: monitoring loop or shutdown signal (whichever comes first)
     tokio::select! {
         _ = monitor_loop => {},
         _ = shutdown_signal => {
@@ -735,3 +5290,288 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Realistic epoch seconds: `should_alert` treats a device never alerted as last alerted at 0
+    const T0: i64 = 1_700_000_000;
+
+    fn failure(severity: Severity) -> CheckFailure {
+        CheckFailure {
+            check_name: "HTTP".to_string(),
+            port: 80,
+            attempted_ips: vec!["10.0.0.1".to_string()],
+            reason: "connection refused".to_string(),
+            severity,
+            kind: FailureKind::Probe,
+            targets_up: 0,
+            targets_total: 1,
+        }
+    }
+
+    fn indices(list: &[usize]) -> HashSet<usize> {
+        list.iter().copied().collect()
+    }
+
+    #[test]
+    fn failure_streak_below_threshold_is_discarded_on_success() {
+        let mut state = AlertState::new();
+        let failures = [failure(Severity::Error)];
+        let failing = BTreeSet::from([0]);
+        let fresh = indices(&[0]);
+
+        assert!(!state.record_failed_round("d", &failures, &failing, &fresh, T0 + 100, 3));
+        assert!(!state.record_failed_round("d", &failures, &failing, &fresh, T0 + 110, 3));
+        assert!(state.record_ok_round("d", &fresh, T0 + 120, 1).is_none());
+        assert!(!state.fail_streak.contains_key("d"));
+        assert!(!state.first_failure.contains_key("d"));
+
+        // The streak starts over
+        assert!(!state.record_failed_round("d", &failures, &failing, &fresh, T0 + 130, 3));
+        assert_eq!(state.fail_streak["d"], 1);
+    }
+
+    #[test]
+    fn threshold_reached_then_recovers_after_n_successes() {
+        let mut state = AlertState::new();
+        let failures = [failure(Severity::Error)];
+        let failing = BTreeSet::from([0]);
+        let fresh = indices(&[0]);
+
+        assert!(!state.record_failed_round("d", &failures, &failing, &fresh, T0 + 100, 2));
+        assert!(state.record_failed_round("d", &failures, &failing, &fresh, T0 + 110, 2));
+        assert!(state.should_alert("d", true, Severity::Error, T0 + 110, 300));
+
+        assert!(state.record_ok_round("d", &fresh, T0 + 120, 2).is_none());
+        let recovery = state.record_ok_round("d", &fresh, T0 + 130, 2).unwrap();
+        assert_eq!(recovery.first_failure_ts, T0 + 100);
+        assert_eq!(recovery.duration_secs(), 30);
+        assert_eq!(recovery.failures.len(), 1);
+        assert!(!state.is_failed.contains_key("d"));
+
+        // A new outage right after the recovery notice is not held back by the old cooldown
+        assert!(state.record_failed_round("d", &failures, &failing, &fresh, T0 + 140, 1));
+        assert!(state.should_alert("d", true, Severity::Error, T0 + 140, 300));
+    }
+
+    #[test]
+    fn escalation_from_warning_to_error_bypasses_cooldown() {
+        let mut state = AlertState::new();
+        assert!(state.should_alert("d", true, Severity::Warning, T0 + 100, 300));
+        assert!(!state.should_alert("d", true, Severity::Warning, T0 + 110, 300));
+        assert!(state.should_alert("d", true, Severity::Error, T0 + 120, 300));
+        assert!(!state.should_alert("d", true, Severity::Error, T0 + 130, 300));
+        assert!(state.should_alert("d", true, Severity::Error, T0 + 420, 300));
+    }
+
+    #[test]
+    fn cached_results_never_advance_a_streak() {
+        let mut state = AlertState::new();
+        let failures = [failure(Severity::Error)];
+        // Check 0 (slow) fails, check 1 (fast) passes and runs every round
+        let failing = BTreeSet::from([0]);
+
+        assert!(!state.record_failed_round(
+            "d",
+            &failures,
+            &failing,
+            &indices(&[0, 1]),
+            T0 + 100,
+            3
+        ));
+        for offset in [105, 110, 115, 120] {
+            assert!(!state.record_failed_round(
+                "d",
+                &failures,
+                &failing,
+                &indices(&[1]),
+                T0 + offset,
+                3
+            ));
+        }
+        assert_eq!(state.fail_streak["d"], 1);
+
+        assert!(!state.record_failed_round(
+            "d",
+            &failures,
+            &failing,
+            &indices(&[0, 1]),
+            T0 + 160,
+            3
+        ));
+        assert!(state.record_failed_round(
+            "d",
+            &failures,
+            &failing,
+            &indices(&[0, 1]),
+            T0 + 220,
+            3
+        ));
+        assert_eq!(state.fail_streak["d"], 3);
+        assert!(state.should_alert("d", true, Severity::Error, T0 + 220, 300));
+
+        // Already confirmed: cached failures keep it failed without growing the streak
+        assert!(state.record_failed_round("d", &failures, &failing, &indices(&[1]), T0 + 225, 3));
+        assert_eq!(state.fail_streak["d"], 3);
+
+        // Recovery only counts fresh runs of the check that was down
+        assert!(state
+            .record_ok_round("d", &indices(&[0, 1]), T0 + 280, 2)
+            .is_none());
+        for offset in [285, 290, 295] {
+            assert!(state
+                .record_ok_round("d", &indices(&[1]), T0 + offset, 2)
+                .is_none());
+        }
+        assert_eq!(state.ok_streak["d"], 1);
+        assert!(state
+            .record_ok_round("d", &indices(&[0, 1]), T0 + 340, 2)
+            .is_some());
+        assert!(!state.outage_checks.contains_key("d"));
+    }
+
+    #[test]
+    fn expand_vars_reads_environment_variables() {
+        env::set_var("PS_TEST_HOST", "db.internal");
+        assert_eq!(
+            expand_vars("tcp://${PS_TEST_HOST}:5432").unwrap(),
+            "tcp://db.internal:5432"
+        );
+        assert_eq!(
+            expand_vars("no variables here").unwrap(),
+            "no variables here"
+        );
+    }
+
+    #[test]
+    fn expand_vars_unset_variable_is_an_error() {
+        env::remove_var("PS_TEST_UNSET");
+        assert_eq!(
+            expand_vars("${PS_TEST_UNSET}").unwrap_err(),
+            "environment variable PS_TEST_UNSET is not set"
+        );
+    }
+
+    #[test]
+    fn expand_vars_default_applies_when_unset_or_empty() {
+        env::remove_var("PS_TEST_DEFAULT_UNSET");
+        env::set_var("PS_TEST_DEFAULT_EMPTY", "");
+        env::set_var("PS_TEST_DEFAULT_SET", "set");
+        assert_eq!(
+            expand_vars("${PS_TEST_DEFAULT_UNSET:-none}").unwrap(),
+            "none"
+        );
+        assert_eq!(
+            expand_vars("${PS_TEST_DEFAULT_EMPTY:-none}").unwrap(),
+            "none"
+        );
+        assert_eq!(expand_vars("${PS_TEST_DEFAULT_SET:-none}").unwrap(), "set");
+        assert_eq!(expand_vars("${PS_TEST_DEFAULT_UNSET:-}").unwrap(), "");
+        // Without a default an empty value is kept as is
+        assert_eq!(expand_vars("[${PS_TEST_DEFAULT_EMPTY}]").unwrap(), "[]");
+    }
+
+    #[test]
+    fn expand_vars_file_content_without_trailing_newlines() {
+        let path = env::temp_dir().join(format!("ps-test-secret-{}", std::process::id()));
+        fs::write(&path, "s3cret\r\n\n").unwrap();
+        let expanded = expand_vars(&format!("${{file:{}}}", path.display()));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(expanded.unwrap(), "s3cret");
+
+        let err = expand_vars("${file:/nonexistent/ps-test-secret}").unwrap_err();
+        assert!(
+            err.starts_with("can't read secret file '/nonexistent/ps-test-secret'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn expand_vars_escape_and_lone_dollar() {
+        assert_eq!(expand_vars("$${NOT_A_VAR}").unwrap(), "${NOT_A_VAR}");
+        assert_eq!(expand_vars("cost $5, $HOME").unwrap(), "cost $5, $HOME");
+        assert_eq!(expand_vars("trailing $").unwrap(), "trailing $");
+    }
+
+    #[test]
+    fn expand_vars_rejects_malformed_expressions() {
+        assert_eq!(
+            expand_vars("x ${OPEN").unwrap_err(),
+            "unterminated '${' in 'x ${OPEN'"
+        );
+        assert_eq!(expand_vars("${}").unwrap_err(), "invalid variable '${}'");
+        assert_eq!(
+            expand_vars("${1ABC}").unwrap_err(),
+            "invalid variable '${1ABC}'"
+        );
+        assert_eq!(
+            expand_vars("${A-B:-x}").unwrap_err(),
+            "invalid variable '${A-B:-x}'"
+        );
+    }
+
+    #[test]
+    fn expand_value_errors_name_the_config_key() {
+        env::remove_var("PS_TEST_MISSING_URL");
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            [settings]
+            interval = 30
+
+            [[notifier]]
+            type = "webhook"
+            url = "https://example.com"
+
+            [[notifier]]
+            type = "webhook"
+            url = "${PS_TEST_MISSING_URL}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            expand_value(&mut value, "").unwrap_err(),
+            "notifier[1].url: environment variable PS_TEST_MISSING_URL is not set"
+        );
+    }
+
+    #[test]
+    fn expand_value_leaves_non_strings_alone() {
+        env::set_var("PS_TEST_TLS", "starttls");
+        let mut value: toml::Value =
+            toml::from_str("port = 25\ntls = \"${PS_TEST_TLS:-none}\"\nto = [\"$${literal}\"]")
+                .unwrap();
+        expand_value(&mut value, "").unwrap();
+        assert_eq!(value["port"].as_integer(), Some(25));
+        assert_eq!(value["tls"].as_str(), Some("starttls"));
+        assert_eq!(value["to"][0].as_str(), Some("${literal}"));
+    }
+
+    #[test]
+    fn read_config_file_expands_before_deserializing_enums() {
+        env::remove_var("PS_TEST_CHECK_TYPE");
+        let path = env::temp_dir().join(format!("ps-test-devices-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+[[device]]
+id = "d1"
+name = "D1"
+group = "g"
+priority = "high"
+ips = ["10.0.0.1"]
+os = "linux"
+location = "lab"
+checks = [{ type = "${PS_TEST_CHECK_TYPE:-tls}", port = 443 }]
+"#,
+        )
+        .unwrap();
+        let loaded = read_config_file::<IncludeFile>(&path);
+        fs::remove_file(&path).unwrap();
+        let (file, lines) = loaded.unwrap();
+        assert_eq!(file.devices[0].checks[0].kind, CheckKind::Tls);
+        assert_eq!(lines, vec![3]);
+    }
+}